@@ -0,0 +1,490 @@
+/*
+ *
+ * Two pass assembler reading the syntax written by Instruction::to_asm:
+ *
+ *     ; comment
+ *     define SPEED 3
+ *     start:  LD I, sprite
+ *             DRW V0, V1, 5
+ *             ADD V0, SPEED
+ *             JP start
+ *     sprite: db 0xF0, 0x90, 0x90, 0x90, 0xF0
+ *
+ * Mnemonics and registers are case insensitive, registers are written V0 to V15
+ * or V0 to VF. Numbers are decimal, 0x hexadecimal or 0b binary. Labels and
+ * constants can be used wherever a number is expected. The XO-CHIP 16 bits
+ * address load is written LD I, long NNNN.
+ *
+ */
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::instructions::Instruction;
+use crate::vm::{ START_ADDR, XO_MEMORY_SIZE };
+
+/// Error in the assembly source, line and column start at 1
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+/// Word of the source with its column
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    column: usize
+}
+
+enum Statement<'a> {
+    Instruction { mnemonic: Token<'a>, operands: Vec<Token<'a>> },
+    Bytes(Vec<Token<'a>>),
+    Words(Vec<Token<'a>>)
+}
+
+/// Statement and the line where it is written
+struct Line<'a> {
+    number: usize,
+    statement: Statement<'a>
+}
+
+enum Operand<'a> {
+    Register(u8),
+
+    /// I, also written [I]
+    I,
+    DT,
+    ST,
+    K,
+    F,
+
+    /// SCHIP big font
+    HF,
+    B,
+
+    /// SCHIP RPL user flags
+    R,
+    Value(u16, Token<'a>),
+
+    /// XO-CHIP 16 bits address, written long NNNN
+    Long(u16)
+}
+
+const MNEMONICS: [&str; 31] = [
+    "CLS", "RET", "SYS", "JP", "CALL", "SE", "SNE", "LD", "ADD", "OR",
+    "AND", "XOR", "SUB", "SUBN", "SHR", "SHL", "RND", "DRW", "SKP", "SKNP",
+    "SCD", "SCR", "SCL", "EXIT", "LOW", "HIGH",
+    "SAVE", "LOAD", "PLANE", "AUDIO", "PITCH"
+];
+
+/// Assemble the source into a ROM loaded at START_ADDR
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler { symbols: HashMap::new(), lines: vec![] };
+    assembler.first_pass(source)?;
+    assembler.second_pass()
+}
+
+struct Assembler<'a> {
+
+    /// Labels and constants values
+    symbols: HashMap<&'a str, u16>,
+    lines: Vec<Line<'a>>
+}
+
+impl<'a> Assembler<'a> {
+
+    /// Collect the statements, define the labels and constants
+    fn first_pass(&mut self, source: &'a str) -> Result<(), AsmError> {
+        let mut addr = START_ADDR;
+
+        for (i, text) in source.lines().enumerate() {
+            let number = i + 1;
+            let code = match text.find(';') {
+                Some(end) => &text[..end],
+                None => text
+            };
+            let mut words = Words { line: text, rest: code };
+
+            let mut word = words.next();
+            while let Some(label) = word.filter(|w| w.text.ends_with(':')) {
+                let name = Token { text: &label.text[..label.text.len() - 1], ..label };
+                self.define(number, name, addr as u16)?;
+                word = words.next();
+            }
+
+            let mnemonic = match word {
+                Some(mnemonic) => mnemonic,
+                None => continue
+            };
+            let statement = match mnemonic.text.to_lowercase().as_str() {
+                "define" => {
+                    let (name, value) = match (words.next(), words.next(), words.next()) {
+                        (Some(name), Some(value), None) => (name, value),
+                        _ => return Err(error(number, mnemonic, "expected define <name> <value>"))
+                    };
+                    let value = self.value(number, value, 0xFFFF)?;
+                    self.define(number, name, value)?;
+                    continue;
+                },
+                "db" => Statement::Bytes(words.operands(number)?),
+                "dw" => Statement::Words(words.operands(number)?),
+                _ => Statement::Instruction { mnemonic, operands: words.operands(number)? }
+            };
+
+            addr += match &statement {
+                Statement::Instruction { operands, .. } if operands.iter().any(|op| long_address(*op).is_some()) => 4,
+                Statement::Instruction { .. } => 2,
+                Statement::Bytes(bytes) => bytes.len(),
+                Statement::Words(words) => 2 * words.len()
+            };
+            if addr > XO_MEMORY_SIZE {
+                return Err(error(number, mnemonic, "program does not fit in memory"));
+            }
+            self.lines.push(Line { number, statement });
+        }
+        Ok(())
+    }
+
+    /// Encode the statements once every label is known
+    fn second_pass(&self) -> Result<Vec<u8>, AsmError> {
+        let mut rom = vec![];
+
+        for line in &self.lines {
+            match &line.statement {
+                Statement::Instruction { mnemonic, operands } => {
+                    rom.extend(self.encode(line.number, *mnemonic, operands)?);
+                },
+                Statement::Bytes(bytes) => for byte in bytes {
+                    rom.push(self.value(line.number, *byte, 0xFF)? as u8);
+                },
+                Statement::Words(words) => for word in words {
+                    rom.extend_from_slice(&self.value(line.number, *word, 0xFFFF)?.to_be_bytes());
+                }
+            }
+        }
+        Ok(rom)
+    }
+
+    fn define(&mut self, line: usize, name: Token<'a>, value: u16) -> Result<(), AsmError> {
+        let valid = name.text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(error(line, name, &format!("invalid name '{}'", name.text)));
+        }
+        if special(name.text).is_some() || register(name.text).is_some() || is_mnemonic(name.text) {
+            return Err(error(line, name, &format!("'{}' is a reserved name", name.text)));
+        }
+        if self.symbols.insert(name.text, value).is_some() {
+            return Err(error(line, name, &format!("'{}' is already defined", name.text)));
+        }
+        Ok(())
+    }
+
+    /// Number, label or constant of at most max
+    fn value(&self, line: usize, token: Token<'a>, max: u16) -> Result<u16, AsmError> {
+        let text = token.text;
+        let value = if text.starts_with(|c: char| c.is_ascii_digit()) {
+            let lower = text.to_lowercase();
+            let parsed = if let Some(digits) = lower.strip_prefix("0x") {
+                u32::from_str_radix(digits, 16)
+            } else if let Some(digits) = lower.strip_prefix("0b") {
+                u32::from_str_radix(digits, 2)
+            } else {
+                lower.parse()
+            };
+            parsed.map_err(|_| error(line, token, &format!("invalid number '{}'", text)))?
+        } else {
+            match self.symbols.get(text) {
+                Some(value) => *value as u32,
+                None => return Err(error(line, token, &format!("undefined name '{}'", text)))
+            }
+        };
+
+        if value > max as u32 {
+            return Err(error(line, token, &format!("{} does not fit in 0x{:X}", text, max)));
+        }
+        Ok(value as u16)
+    }
+
+    fn operand(&self, line: usize, token: Token<'a>) -> Result<Operand<'a>, AsmError> {
+        if let Some(operand) = special(token.text) {
+            return Ok(operand);
+        }
+        if let Some(x) = register(token.text) {
+            return Ok(Operand::Register(x));
+        }
+        if let Some(addr) = long_address(token) {
+            return self.value(line, addr, 0xFFFF).map(Operand::Long);
+        }
+        self.value(line, token, 0xFFFF).map(|value| Operand::Value(value, token))
+    }
+
+    /// Opcode bytes of the instruction, followed by the address of LD I, long NNNN
+    fn encode(&self, line: usize, mnemonic: Token<'a>, tokens: &[Token<'a>]) -> Result<Vec<u8>, AsmError> {
+        let name = mnemonic.text.to_uppercase();
+        if !is_mnemonic(&name) {
+            return Err(error(line, mnemonic, &format!("unknown mnemonic '{}'", mnemonic.text)));
+        }
+        let operands = tokens.iter()
+            .map(|token| self.operand(line, *token))
+            .collect::<Result<Vec<Operand>, AsmError>>()?;
+
+        let fit = |value: u16, token: Token<'a>, max: u16| {
+            if value > max {
+                Err(error(line, token, &format!("{} does not fit in 0x{:X}", token.text, max)))
+            } else {
+                Ok(value)
+            }
+        };
+        let addr = |value: u16, token| fit(value, token, 0xFFF);
+        let byte = |value: u16, token| fit(value, token, 0xFF).map(|value| value as u8);
+
+        use Operand::*;
+        let (x, y) = match operands.as_slice() {
+            [Register(x), Register(y), ..] => (*x, *y),
+            [Register(x), ..] | [_, Register(x)] => (*x, *x),
+            _ => (0, 0)
+        };
+        let instruction = match (name.as_str(), operands.as_slice()) {
+            ("CLS", []) => Instruction::Clear,
+            ("RET", []) => Instruction::Return,
+            ("SCD", [Value(n, t)]) => Instruction::ScrollDown { n: fit(*n, *t, 0xF)? as u8 },
+            ("SCR", []) => Instruction::ScrollRight,
+            ("SCL", []) => Instruction::ScrollLeft,
+            ("EXIT", []) => Instruction::Exit,
+            ("LOW", []) => Instruction::LowResolution,
+            ("HIGH", []) => Instruction::HighResolution,
+            ("SYS", [Value(a, t)]) => Instruction::CallProgram { addr: addr(*a, *t)? },
+            ("JP", [Value(a, t)]) => Instruction::Goto { addr: addr(*a, *t)? },
+            ("JP", [Register(0), Value(a, t)]) => Instruction::JumpToAddress { addr: addr(*a, *t)? },
+            ("CALL", [Value(a, t)]) => Instruction::CallSubroutine { addr: addr(*a, *t)? },
+            ("SE", [Register(_), Value(v, t)]) => Instruction::SkipEqualU8 { x, value: byte(*v, *t)? },
+            ("SE", [Register(_), Register(_)]) => Instruction::SkipEqualReg { x, y },
+            ("SNE", [Register(_), Value(v, t)]) => Instruction::SkipNotEqualU8 { x, value: byte(*v, *t)? },
+            ("SNE", [Register(_), Register(_)]) => Instruction::SkipNotEqualReg { x, y },
+            ("LD", [Register(_), Value(v, t)]) => Instruction::SetFromU8 { x, value: byte(*v, *t)? },
+            ("LD", [Register(_), Register(_)]) => Instruction::SetFromReg { x, y },
+            ("LD", [I, Value(a, t)]) => Instruction::StoreAddress { addr: addr(*a, *t)? },
+            ("LD", [I, Long(a)]) => Instruction::LongStoreAddress { addr: *a },
+            ("LD", [Register(_), DT]) => Instruction::SetFromDelayTimer { x },
+            ("LD", [Register(_), K]) => Instruction::AwaitKeyPressed { x },
+            ("LD", [DT, Register(_)]) => Instruction::SetDelayTimer { x },
+            ("LD", [ST, Register(_)]) => Instruction::SetSoundTimer { x },
+            ("LD", [F, Register(_)]) => Instruction::SetIToSpriteAddress { x },
+            ("LD", [HF, Register(_)]) => Instruction::SetIToBigSpriteAddress { x },
+            ("LD", [B, Register(_)]) => Instruction::StoreAtIAsDecimal { x },
+            ("LD", [I, Register(_)]) => Instruction::DumpToMemory { x },
+            ("LD", [Register(_), I]) => Instruction::LoadFromMemory { x },
+            ("LD", [R, Register(_)]) => Instruction::StoreFlags { x },
+            ("LD", [Register(_), R]) => Instruction::LoadFlags { x },
+            ("ADD", [Register(_), Value(v, t)]) => Instruction::AddU8 { x, value: byte(*v, *t)? },
+            ("ADD", [Register(_), Register(_)]) => Instruction::AddReg { x, y },
+            ("ADD", [I, Register(_)]) => Instruction::AddToI { x },
+            ("OR", [Register(_), Register(_)]) => Instruction::OrReg { x, y },
+            ("AND", [Register(_), Register(_)]) => Instruction::AndReg { x, y },
+            ("XOR", [Register(_), Register(_)]) => Instruction::XorReg { x, y },
+            ("SUB", [Register(_), Register(_)]) => Instruction::SubReg { x, y },
+            ("SHR", [Register(_)]) | ("SHR", [Register(_), Register(_)]) => Instruction::ShiftRight { x, y },
+            ("SUBN", [Register(_), Register(_)]) => Instruction::RevSubReg { x, y },
+            ("SHL", [Register(_)]) | ("SHL", [Register(_), Register(_)]) => Instruction::ShiftLeft { x, y },
+            ("RND", [Register(_), Value(v, t)]) => Instruction::Rand { x, value: byte(*v, *t)? },
+            ("DRW", [Register(_), Register(_), Value(n, t)]) => Instruction::Draw { x, y, n: fit(*n, *t, 0xF)? as u8 },
+            ("SKP", [Register(_)]) => Instruction::SkipIfKeyPressed { x },
+            ("SKNP", [Register(_)]) => Instruction::SkipIfNotKeyPressed { x },
+            ("SAVE", [Register(_), Register(_)]) => Instruction::SaveRange { x, y },
+            ("LOAD", [Register(_), Register(_)]) => Instruction::LoadRange { x, y },
+            ("PLANE", [Value(n, t)]) => Instruction::SelectPlanes { n: fit(*n, *t, 0xF)? as u8 },
+            ("AUDIO", []) => Instruction::LoadAudioPattern,
+            ("PITCH", [Register(_)]) => Instruction::SetPitch { x },
+            _ => return Err(error(line, mnemonic, &format!("invalid operands for {}", name)))
+        };
+        let opcode = instruction.encode()
+            .ok_or_else(|| error(line, mnemonic, &format!("{} can't be encoded", instruction.to_asm())))?;
+
+        let mut bytes = opcode.to_be_bytes().to_vec();
+        if let Instruction::LongStoreAddress { addr } = instruction {
+            bytes.extend_from_slice(&addr.to_be_bytes());
+        }
+        Ok(bytes)
+    }
+}
+
+/// Whitespace separated words of a line, then its comma separated operands
+struct Words<'a> {
+    line: &'a str,
+    rest: &'a str
+}
+
+impl<'a> Words<'a> {
+    fn token(&self, text: &'a str) -> Token<'a> {
+        Token { text, column: text.as_ptr() as usize - self.line.as_ptr() as usize + 1 }
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        self.rest = &rest[end..];
+        Some(self.token(&rest[..end]))
+    }
+
+    fn operands(&mut self, line: usize) -> Result<Vec<Token<'a>>, AsmError> {
+        if self.rest.trim().is_empty() {
+            return Ok(vec![]);
+        }
+        let mut operands = vec![];
+        for part in self.rest.split(',') {
+            let text = part.trim();
+            if text.is_empty() {
+                let column = part.as_ptr() as usize - self.line.as_ptr() as usize + 1;
+                return Err(AsmError { line, column, message: "missing operand".to_owned() });
+            }
+            // Point at the operand itself, not the whitespace around it
+            let start = part.len() - part.trim_start().len();
+            operands.push(self.token(&part[start..start + text.len()]));
+        }
+        Ok(operands)
+    }
+}
+
+fn error(line: usize, token: Token<'_>, message: &str) -> AsmError {
+    AsmError { line, column: token.column, message: message.to_owned() }
+}
+
+/// Address of a long NNNN operand
+fn long_address(token: Token<'_>) -> Option<Token<'_>> {
+    let prefix = token.text.get(..4).filter(|prefix| prefix.eq_ignore_ascii_case("long"))?;
+    let rest = &token.text[prefix.len()..];
+    let addr = rest.trim_start();
+    if addr.len() == rest.len() || addr.is_empty() {
+        return None;
+    }
+    Some(Token { text: addr, column: token.column + token.text.len() - addr.len() })
+}
+
+fn is_mnemonic(name: &str) -> bool {
+    MNEMONICS.contains(&name.to_uppercase().as_str())
+}
+
+/// Register V0 to V15, also written V0 to VF
+fn register(name: &str) -> Option<u8> {
+    let index = name.strip_prefix('V').or_else(|| name.strip_prefix('v'))?;
+    let x = match index.len() {
+        1 => u8::from_str_radix(index, 16).ok()?,
+        2 => index.parse().ok()?,
+        _ => return None
+    };
+    if x < 16 { Some(x) } else { None }
+}
+
+fn special<'a>(name: &str) -> Option<Operand<'a>> {
+    match name.to_uppercase().as_str() {
+        "I" | "[I]" => Some(Operand::I),
+        "DT" => Some(Operand::DT),
+        "ST" => Some(Operand::ST),
+        "K" => Some(Operand::K),
+        "F" => Some(Operand::F),
+        "HF" => Some(Operand::HF),
+        "B" => Some(Operand::B),
+        "R" => Some(Operand::R),
+        _ => None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            ; draw a sprite then loop
+            define SPEED 3
+            start:  LD I, sprite
+                    drw v0, VA, 5     ; registers are case insensitive
+                    ADD V0, SPEED
+            loop:   JP loop
+            sprite: db 0xF0, 0b10010000, 144
+                    dw 0x90F0, start
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(rom, vec![
+            0xA2, 0x08, 0xD0, 0xA5, 0x70, 0x03, 0x12, 0x06,
+            0xF0, 0x90, 0x90, 0x90, 0xF0, 0x02, 0x00
+        ]);
+    }
+
+    #[test]
+    fn test_to_asm_round_trip() {
+        let opcodes: Vec<u16> = vec![
+            0x00E0, 0x00EE, 0x0123, 0x1234, 0x2345, 0x3A42, 0x4B43, 0x5120, 0x6FFF, 0x7001,
+            0x8120, 0x8121, 0x8122, 0x8123, 0x8124, 0x8125, 0x8126, 0x8127, 0x812E, 0x9AB0,
+            0xA050, 0xBFFF, 0xC10F, 0xD125, 0xE19E, 0xE2A1, 0xF307, 0xF40A, 0xF515, 0xF618,
+            0xF71E, 0xF829, 0xF933, 0xFA55, 0xFF65, 0x00C3, 0x00FB, 0x00FC, 0x00FD, 0x00FE,
+            0x00FF, 0xD120, 0xF130, 0xF775, 0xF385, 0x5122, 0x5AB3, 0xF201, 0xF002, 0xF43A
+        ];
+        let source: Vec<String> = opcodes.iter().map(|op| Instruction::from(*op).to_asm()).collect();
+        let rom = assemble(&source.join("\n")).unwrap();
+
+        let expected: Vec<u8> = opcodes.iter().flat_map(|op| op.to_be_bytes().to_vec()).collect();
+        assert_eq!(rom, expected);
+    }
+
+    #[test]
+    fn test_disassemble_round_trip() {
+        // CLS; JP 0x206; data 0x0000; LD V0, 1; data 0xF801, 0xF090, 0xAB
+        let rom = [0x00, 0xE0, 0x12, 0x06, 0x00, 0x00, 0x60, 0x01, 0xF8, 0x01, 0xF0, 0x90, 0xAB];
+        let vm = VM::try_from(&rom[..]).unwrap();
+        let listing: Vec<String> = vm.get_program().into_iter().map(|(_, line)| line).collect();
+
+        assert_eq!(listing[2], "dw 0x0000");
+        assert_eq!(listing[4], "PLANE 8");
+        assert_eq!(assemble(&listing.join("\n")).unwrap(), rom.to_vec());
+    }
+
+    #[test]
+    fn test_long_address() {
+        let rom = assemble("LD I, long data\nSAVE V0, V3\ndata: db 1").unwrap();
+        assert_eq!(rom, vec![0xF0, 0x00, 0x02, 0x06, 0x50, 0x32, 0x01]);
+
+        let err = assemble("LD I, long 0x10000").unwrap_err();
+        assert_eq!((err.column, err.message.as_str()), (12, "0x10000 does not fit in 0xFFFF"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |source: &str| assemble(source).unwrap_err();
+
+        assert_eq!(err("CLS\n  JP nowhere"), AsmError {
+            line: 2,
+            column: 6,
+            message: "undefined name 'nowhere'".to_owned()
+        });
+        assert_eq!(err("  FOO V1").message, "unknown mnemonic 'FOO'");
+        assert_eq!(err("LD V1, 256").column, 8);
+        assert_eq!(err("LD V1, DT, 2").message, "invalid operands for LD");
+        assert_eq!(err("ADD V1,, V2").column, 8);
+        assert_eq!(err("a: CLS\na: RET").message, "'a' is already defined");
+        assert_eq!(err("VA: CLS").message, "'VA' is a reserved name");
+        assert_eq!(err("DRW V1, V2, 16").message, "16 does not fit in 0xF");
+        assert_eq!(err("define X").line, 1);
+        assert_eq!(err("SYS 0xE0").message, "SYS 0xE0 can't be encoded");
+    }
+}
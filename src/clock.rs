@@ -0,0 +1,279 @@
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use log::debug;
+
+use crate::vm::TIMER_FREQUENCY;
+
+/// Instructions per second of most CHIP-8 games
+pub const DEFAULT_IPS: u32 = 700;
+
+/// Bounds of the speed multiplier
+pub const MIN_SPEED: f64 = 0.1;
+pub const MAX_SPEED: f64 = 10.0;
+
+/// Speeds stepped through by speed_up and slow_down, fixed so that normal speed is
+/// always reached again
+pub const SPEED_STEPS: [f64; 7] = [MIN_SPEED, 0.25, 0.5, 1.0, 2.0, 4.0, MAX_SPEED];
+
+/// Paces the instructions against wall time in 60Hz frames
+#[derive(Debug)]
+pub struct Clock {
+
+    /// Instructions per second at normal speed
+    ips: u32,
+
+    /// Wall time multiplier, 2 runs twice as fast and 0.5 in slow motion
+    speed: f64,
+
+    /// Run the frames back to back, as fast as the host allows
+    turbo: bool,
+
+    paused: bool,
+
+    /// Frames still to run while paused
+    advance: u32,
+
+    /// Instructions per second not run yet, carried to the next frames so that
+    /// the frames of a second add up to ips instructions
+    remainder: u32,
+
+    /// End of the current frame
+    deadline: Option<Instant>,
+
+    /// Start and instructions run of the current achieved IPS measure
+    measure_start: Option<Instant>,
+    measure_instructions: u64,
+
+    /// Instructions run per second of wall time during the last measure
+    achieved_ips: f64
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new(DEFAULT_IPS)
+    }
+}
+
+impl Clock {
+    pub fn new(ips: u32) -> Self {
+        Clock {
+            ips: ips.max(1),
+            speed: 1.0,
+            turbo: false,
+            paused: false,
+            advance: 0,
+            remainder: 0,
+            deadline: None,
+            measure_start: None,
+            measure_instructions: 0,
+            achieved_ips: 0.0
+        }
+    }
+
+    pub fn ips(&self) -> u32 {
+        self.ips
+    }
+
+    pub fn set_ips(&mut self, ips: u32) {
+        self.ips = ips.max(1);
+        self.remainder = 0;
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Clamped between MIN_SPEED and MAX_SPEED
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    /// Next step of SPEED_STEPS above the current speed
+    pub fn speed_up(&mut self) {
+        if let Some(speed) = SPEED_STEPS.iter().find(|speed| **speed > self.speed) {
+            self.speed = *speed;
+        }
+    }
+
+    /// Next step of SPEED_STEPS below the current speed
+    pub fn slow_down(&mut self) {
+        if let Some(speed) = SPEED_STEPS.iter().rev().find(|speed| **speed < self.speed) {
+            self.speed = *speed;
+        }
+    }
+
+    pub fn is_turbo(&self) -> bool {
+        self.turbo
+    }
+
+    pub fn set_turbo(&mut self, turbo: bool) {
+        self.turbo = turbo;
+        self.deadline = None;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.advance = 0;
+    }
+
+    /// Run a single frame while paused
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.advance += 1;
+        }
+    }
+
+    /// Instructions measured during the last second of wall time, 0 before the first second
+    pub fn achieved_ips(&self) -> f64 {
+        self.achieved_ips
+    }
+
+    /// Instructions to run in the frame starting, None when paused
+    pub fn start_frame(&mut self) -> Option<usize> {
+        if self.paused {
+            if self.advance == 0 {
+                return None;
+            }
+            self.advance -= 1;
+        }
+        let total = self.ips + self.remainder;
+        self.remainder = total % TIMER_FREQUENCY;
+        Some((total / TIMER_FREQUENCY) as usize)
+    }
+
+    /// Wall time of a frame at the current speed, None in turbo mode. Paused frames
+    /// last 1/60s to keep polling the frontend at the same rate
+    pub fn frame_duration(&self) -> Option<Duration> {
+        let frame = Duration::from_secs(1) / TIMER_FREQUENCY;
+        match (self.paused, self.turbo) {
+            (true, _) => Some(frame),
+            (false, true) => None,
+            (false, false) => Some(frame.div_f64(self.speed))
+        }
+    }
+
+    /// Account the instructions run during the frame then sleep until its end
+    pub fn end_frame(&mut self, instructions: usize) {
+        let now = Instant::now();
+        self.measure(now, instructions);
+
+        let duration = match self.frame_duration() {
+            Some(duration) => duration,
+            None => {
+                self.deadline = None;
+                return;
+            }
+        };
+        let deadline = self.deadline.unwrap_or(now) + duration;
+        if deadline > now {
+            thread::sleep(deadline - now);
+            self.deadline = Some(deadline);
+        } else {
+            self.deadline = Some(now);
+        }
+    }
+
+    fn measure(&mut self, now: Instant, instructions: usize) {
+        let start = *self.measure_start.get_or_insert(now);
+        self.measure_instructions += instructions as u64;
+
+        let elapsed = now - start;
+        if elapsed >= Duration::from_secs(1) {
+            self.achieved_ips = self.measure_instructions as f64 / elapsed.as_secs_f64();
+            debug!("{:.0} instructions per second", self.achieved_ips);
+            self.measure_start = Some(now);
+            self.measure_instructions = 0;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instructions_per_frame() {
+        let mut clock = Clock::default();
+        let frames: Vec<usize> = (0..60).map(|_| clock.start_frame().unwrap()).collect();
+        assert_eq!(frames.iter().sum::<usize>(), 700);
+        assert_eq!(&frames[..3], &[11, 12, 12]);
+
+        clock.set_ips(60);
+        assert_eq!(clock.start_frame(), Some(1));
+    }
+
+    #[test]
+    fn test_pause_advance() {
+        let mut clock = Clock::new(600);
+        clock.advance_frame();
+        assert_eq!(clock.start_frame(), Some(10));
+
+        clock.set_paused(true);
+        assert_eq!(clock.start_frame(), None);
+        clock.advance_frame();
+        clock.advance_frame();
+        assert_eq!(clock.start_frame(), Some(10));
+        assert_eq!(clock.start_frame(), Some(10));
+        assert_eq!(clock.start_frame(), None);
+
+        clock.set_paused(false);
+        assert_eq!(clock.start_frame(), Some(10));
+    }
+
+    #[test]
+    fn test_frame_duration() {
+        let mut clock = Clock::default();
+        let frame = Duration::from_secs(1) / TIMER_FREQUENCY;
+        assert_eq!(clock.frame_duration(), Some(frame));
+
+        clock.set_speed(0.5);
+        assert_eq!(clock.frame_duration(), Some(frame * 2));
+        clock.set_speed(100.0);
+        assert_eq!(clock.speed(), MAX_SPEED);
+
+        clock.set_turbo(true);
+        assert_eq!(clock.frame_duration(), None);
+        clock.set_paused(true);
+        assert_eq!(clock.frame_duration(), Some(frame));
+    }
+
+    #[test]
+    fn test_speed_steps() {
+        let mut clock = Clock::default();
+        for _ in 0..10 {
+            clock.slow_down();
+        }
+        assert_eq!(clock.speed(), MIN_SPEED);
+        for _ in 0..3 {
+            clock.speed_up();
+        }
+        assert_eq!(clock.speed(), 1.0);
+
+        clock.set_speed(3.0);
+        clock.slow_down();
+        assert_eq!(clock.speed(), 2.0);
+        for _ in 0..10 {
+            clock.speed_up();
+        }
+        assert_eq!(clock.speed(), MAX_SPEED);
+    }
+
+    #[test]
+    fn test_achieved_ips() {
+        let mut clock = Clock::default();
+        let start = Instant::now();
+        clock.measure(start, 0);
+        for frame in 1..=60 {
+            clock.measure(start + Duration::from_millis(frame * 25), 10);
+        }
+        assert_eq!(clock.achieved_ips(), 400.0);
+        clock.measure(start + Duration::from_millis(1600), 10);
+        assert_eq!(clock.achieved_ips(), 400.0);
+    }
+}
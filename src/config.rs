@@ -0,0 +1,322 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use docopt::Docopt;
+use log::LevelFilter;
+
+use crate::clock::{ MIN_SPEED, MAX_SPEED };
+use crate::dump::DumpFormat;
+use crate::timing::TimingModel;
+use crate::vm::Quirks;
+use crate::vm::quirks::PRESETS;
+
+const USAGE: &str = "
+Chip8 emulator.
+
+Usage:
+    chip8 [options] <file>
+    chip8 asm [options] <file>
+    chip8 (-h | --help)
+    chip8 --version
+
+Options:
+    -h, --help              Show this screen.
+    --version               Show version.
+    -d, --disassemble       Print the program instructions instead of running it.
+    --debug                 Run the program in the interactive step debugger.
+    -c, --clock <hz>        Instructions executed per second [default: 700].
+    --speed <factor>        Speed multiplier, from 0.1 (slow motion) to 10 [default: 1].
+    --turbo                 Run as fast as possible, the clock only sets the instructions per frame.
+    --paused                Start paused: space resumes and n advances a single frame.
+    -t, --timing <model>    Instruction timing: fixed (--clock instructions per second)
+                            or vip (COSMAC VIP instruction durations) [default: fixed].
+    -q, --quirks <preset>   Compatibility quirks preset: vip, chip48, schip or xochip [default: vip].
+    -s, --scale <n>         Display scale factor, from 1 to 16 [default: 1].
+    -f, --frontend <name>   Frontend used to run the program: terminal or headless [default: terminal].
+    --headless              Use the headless frontend: run --cycles instructions then dump the VM state.
+    --cycles <n>            Instructions executed by the headless frontend [default: 1000].
+    --dump <format>         State dump format of the headless frontend: ascii or pbm [default: ascii].
+    -o, --output <path>     File receiving the state dump, stdout by default.
+                            With asm, file receiving the ROM, <file>.rom by default.
+    --rewind-depth <n>      Snapshots kept to rewind, 0 to disable.
+                            Defaults to 100, or 0 with the headless frontend.
+    --rewind-interval <n>   Frames between two rewind snapshots [default: 6].
+    --load-state <slot>     Start from the save state slot (0 to 9).
+    --save-state <slot>     Save the state in the slot (0 to 9) when the emulator stops.
+    --seed <n>              Seed of the random number generator (CXNN), random by default.
+    --flags-dir <path>      Directory keeping the SCHIP RPL user flags (high scores) of each ROM.
+                            Defaults to $XDG_DATA_HOME/chip8/flags or ~/.local/share/chip8/flags,
+                            headless runs only keep them with this option.
+    --no-flags              Do not load nor save the RPL user flags.
+    -l, --log-level <lvl>   Log level: off, error, warn, info, debug or trace.
+                            Defaults to the RUST_LOG environment variable.
+";
+
+/// Snapshots kept to rewind by interactive frontends
+const DEFAULT_REWIND_DEPTH: usize = 100;
+
+/// Frontend running the VM
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrontendKind {
+    Terminal,
+    Headless
+}
+
+impl FromStr for FrontendKind {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "terminal" => Ok(FrontendKind::Terminal),
+            "headless" => Ok(FrontendKind::Headless),
+            _ => Err(())
+        }
+    }
+}
+
+/// Invalid value given to a command line argument
+#[derive(Debug)]
+pub struct ConfigError {
+    pub argument: &'static str,
+    pub value: String,
+    pub reason: String
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid value '{}' for {}: {}", self.value, self.argument, self.reason)
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Command line configuration
+#[derive(Debug)]
+pub struct Config {
+
+    /// Path of the ROM
+    pub file: String,
+
+    /// Assemble the source file into a ROM
+    pub assemble: bool,
+
+    /// Disassemble the ROM instead of running it
+    pub disassemble: bool,
+
+    /// Run the ROM in the step debugger
+    pub debug: bool,
+
+    /// Instructions per second
+    pub clock: u32,
+
+    /// Speed multiplier of the clock
+    pub speed: f64,
+
+    /// Run the frames back to back instead of pacing them at 60Hz
+    pub turbo: bool,
+
+    /// Start with the clock paused
+    pub paused: bool,
+
+    pub timing: TimingModel,
+
+    pub quirks: Quirks,
+
+    /// Display scale factor
+    pub scale: u32,
+
+    pub frontend: FrontendKind,
+
+    /// Instructions executed by the headless frontend
+    pub cycles: u64,
+
+    /// State dump format of the headless frontend
+    pub dump: DumpFormat,
+
+    /// File receiving the state dump or the assembled ROM, None for the default
+    pub output: Option<String>,
+
+    /// Snapshots kept to rewind, 0 when disabled
+    pub rewind_depth: usize,
+
+    /// Frames between two rewind snapshots
+    pub rewind_interval: u32,
+
+    /// Save state slot loaded on start
+    pub load_state: Option<u8>,
+
+    /// Save state slot written on exit
+    pub save_state: Option<u8>,
+
+    /// Seed of the random number generator
+    pub seed: Option<u64>,
+
+    /// Directory persisting the RPL user flags, None when disabled
+    pub flags_dir: Option<PathBuf>,
+
+    /// Log level, None to use RUST_LOG
+    pub log_level: Option<LevelFilter>
+}
+
+impl Config {
+
+    /// Parse the process arguments, exits with the usage on syntax errors
+    pub fn from_args() -> Result<Config, ConfigError> {
+        let version = format!("chip8 {}", env!("CARGO_PKG_VERSION"));
+        let args = Docopt::new(USAGE)
+            .and_then(|d| d.version(Some(version)).parse())
+            .unwrap_or_else(|e| e.exit());
+
+        let frontend = if args.get_bool("--headless") {
+            FrontendKind::Headless
+        } else {
+            parse_frontend(args.get_str("--frontend"))?
+        };
+        let headless = frontend == FrontendKind::Headless;
+
+        Ok(Config {
+            file: args.get_str("<file>").to_owned(),
+            assemble: args.get_bool("asm"),
+            disassemble: args.get_bool("--disassemble"),
+            debug: args.get_bool("--debug"),
+            clock: parse_number("--clock", args.get_str("--clock"), 1, 1_000_000)?,
+            speed: parse_number("--speed", args.get_str("--speed"), MIN_SPEED, MAX_SPEED)?,
+            turbo: args.get_bool("--turbo"),
+            paused: args.get_bool("--paused"),
+            timing: parse_timing(args.get_str("--timing"))?,
+            quirks: parse_quirks(args.get_str("--quirks"))?,
+            scale: parse_number("--scale", args.get_str("--scale"), 1, 16)?,
+            frontend,
+            cycles: parse_number("--cycles", args.get_str("--cycles"), 0, u64::MAX)?,
+            dump: parse_dump_format(args.get_str("--dump"))?,
+            output: match args.get_str("--output") {
+                "" => None,
+                output => Some(output.to_owned())
+            },
+            rewind_depth: match args.get_str("--rewind-depth") {
+                "" if headless => 0,
+                "" => DEFAULT_REWIND_DEPTH,
+                depth => parse_number("--rewind-depth", depth, 0, 100_000)?
+            },
+            rewind_interval: parse_number("--rewind-interval", args.get_str("--rewind-interval"), 1, 3600)?,
+            load_state: parse_slot("--load-state", args.get_str("--load-state"))?,
+            save_state: parse_slot("--save-state", args.get_str("--save-state"))?,
+            seed: match args.get_str("--seed") {
+                "" => None,
+                seed => Some(parse_number("--seed", seed, 0, u64::MAX)?)
+            },
+            flags_dir: match args.get_str("--flags-dir") {
+                _ if args.get_bool("--no-flags") => None,
+                "" if headless => None,
+                "" => default_flags_dir(),
+                dir => Some(PathBuf::from(dir))
+            },
+            log_level: match args.get_str("--log-level") {
+                "" => None,
+                level => Some(parse_log_level(level)?)
+            }
+        })
+    }
+}
+
+/// $XDG_DATA_HOME/chip8/flags, or ~/.local/share/chip8/flags when it is not set
+fn default_flags_dir() -> Option<PathBuf> {
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))?;
+    Some(data_home.join("chip8").join("flags"))
+}
+
+fn parse_number<T>(argument: &'static str, value: &str, min: T, max: T) -> Result<T, ConfigError>
+    where T: FromStr + PartialOrd + fmt::Display
+{
+    let error = |reason: String| ConfigError { argument, value: value.to_owned(), reason };
+
+    match value.parse::<T>() {
+        Ok(n) if n >= min && n <= max => Ok(n),
+        Ok(_) => Err(error(format!("must be between {} and {}", min, max))),
+        Err(_) => Err(error("not a number".to_owned()))
+    }
+}
+
+fn parse_slot(argument: &'static str, value: &str) -> Result<Option<u8>, ConfigError> {
+    match value {
+        "" => Ok(None),
+        slot => parse_number(argument, slot, 0, 9).map(Some)
+    }
+}
+
+fn parse_quirks(value: &str) -> Result<Quirks, ConfigError> {
+    Quirks::from_preset(value).ok_or_else(|| ConfigError {
+        argument: "--quirks",
+        value: value.to_owned(),
+        reason: format!("expected one of {}", PRESETS.join(", "))
+    })
+}
+
+fn parse_timing(value: &str) -> Result<TimingModel, ConfigError> {
+    value.parse().map_err(|_| ConfigError {
+        argument: "--timing",
+        value: value.to_owned(),
+        reason: "expected fixed or vip".to_owned()
+    })
+}
+
+fn parse_frontend(value: &str) -> Result<FrontendKind, ConfigError> {
+    value.parse().map_err(|_| ConfigError {
+        argument: "--frontend",
+        value: value.to_owned(),
+        reason: "expected terminal or headless".to_owned()
+    })
+}
+
+fn parse_dump_format(value: &str) -> Result<DumpFormat, ConfigError> {
+    value.parse().map_err(|_| ConfigError {
+        argument: "--dump",
+        value: value.to_owned(),
+        reason: "expected ascii or pbm".to_owned()
+    })
+}
+
+fn parse_log_level(value: &str) -> Result<LevelFilter, ConfigError> {
+    value.parse().map_err(|_| ConfigError {
+        argument: "--log-level",
+        value: value.to_owned(),
+        reason: "expected off, error, warn, info, debug or trace".to_owned()
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("--scale", "4", 1, 16).unwrap(), 4);
+
+        let err = parse_number("--scale", "17", 1, 16).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid value '17' for --scale: must be between 1 and 16");
+
+        let err = parse_number("--clock", "fast", 1, 1000).unwrap_err();
+        assert_eq!(err.argument, "--clock");
+
+        assert_eq!(parse_number("--speed", "0.5", MIN_SPEED, MAX_SPEED).unwrap(), 0.5);
+        assert!(parse_number("--speed", "0", MIN_SPEED, MAX_SPEED).is_err());
+    }
+
+    #[test]
+    fn test_parse_presets() {
+        assert_eq!(parse_quirks("schip").unwrap(), Quirks::schip());
+        assert_eq!(parse_quirks("nes").unwrap_err().argument, "--quirks");
+        assert_eq!(parse_frontend("headless").unwrap(), FrontendKind::Headless);
+        assert_eq!(parse_timing("vip").unwrap(), TimingModel::Vip);
+        assert_eq!(parse_dump_format("pbm").unwrap(), DumpFormat::Pbm);
+        assert_eq!(parse_log_level("debug").unwrap(), LevelFilter::Debug);
+        assert!(parse_log_level("loud").is_err());
+    }
+}
@@ -0,0 +1,602 @@
+use std::collections::{ BTreeMap, BTreeSet };
+use std::fmt;
+use std::io::{ self, BufRead, Write };
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::dump::{ self, DumpFormat };
+use crate::instructions::Instruction;
+use crate::vm::{ VM, VmError, KeyEvent };
+
+const PROMPT: &str = "(chip8) ";
+
+/// Instructions listed before and after the address by list
+const LIST_CONTEXT: usize = 5;
+
+/// Bytes shown by x when no length is given
+const HEXDUMP_LEN: usize = 64;
+
+/// Instructions run by continue and next before giving control back, so that
+/// endless loops (timer polls, ...) do not hang the debugger
+const RUN_LIMIT: usize = 1_000_000;
+
+const HELP: &str = "\
+Commands, addresses are hexadecimal:
+    s, step [n]               Execute n instructions (default 1)
+    n, next                   Execute one instruction, running a CALL until it returns
+    c, continue [n]           Run until a breakpoint, a watchpoint, the end of the program
+                              or n instructions (default 1000000)
+    b, break <addr>           Break before executing the instruction at addr
+    b, break op <mnemonic>    Break before executing any instruction of this kind (e.g. DRW)
+    b, break                  List breakpoints and watchpoints
+    d, delete <addr>          Remove the breakpoint at addr
+    d, delete op <mnemonic>   Remove the breakpoint on an instruction kind
+    w, watch <addr> [r|w|rw]  Break after an instruction reads and/or writes addr (default rw)
+    unwatch <addr>            Remove the watchpoint at addr
+    r, regs                   Show PC, I, registers, stack and timers
+    x <addr> [len]            Hexdump len bytes of memory (default 64)
+    l, list [addr]            Disassemble around addr (default PC)
+    screen                    Show the display
+    key <k>                   Press and release the key k (0 to F)
+    h, help                   Show this help
+    q, quit                   Exit the debugger
+An empty line repeats the last command.";
+
+/// Kind of memory access
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Write
+}
+
+/// Accesses triggering a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Watch {
+    read: bool,
+    write: bool
+}
+
+impl Watch {
+    fn matches(self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write
+        }
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.read, self.write) {
+            (true, false) => write!(f, "r"),
+            (false, true) => write!(f, "w"),
+            _ => write!(f, "rw")
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Step(usize),
+    Next,
+    Continue(usize),
+    Break(usize),
+    BreakOp(String),
+    ListBreakpoints,
+    Delete(usize),
+    DeleteOp(String),
+    Watch(usize, Watch),
+    Unwatch(usize),
+    Registers,
+    Hexdump(usize, usize),
+    List(Option<usize>),
+    Screen,
+    Key(u8),
+    Help,
+    Quit
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words.as_slice() {
+            ["s"] | ["step"] => Command::Step(1),
+            ["s", n] | ["step", n] => Command::Step(parse_count(n)?),
+            ["n"] | ["next"] => Command::Next,
+            ["c"] | ["continue"] => Command::Continue(RUN_LIMIT),
+            ["c", n] | ["continue", n] => Command::Continue(parse_count(n)?),
+            ["b"] | ["break"] => Command::ListBreakpoints,
+            ["b", "op", op] | ["break", "op", op] => Command::BreakOp(op.to_uppercase()),
+            ["b", addr] | ["break", addr] => Command::Break(parse_addr(addr)?),
+            ["d", "op", op] | ["delete", "op", op] => Command::DeleteOp(op.to_uppercase()),
+            ["d", addr] | ["delete", addr] => Command::Delete(parse_addr(addr)?),
+            ["w", addr] | ["watch", addr] => Command::Watch(parse_addr(addr)?, Watch { read: true, write: true }),
+            ["w", addr, kind] | ["watch", addr, kind] => Command::Watch(parse_addr(addr)?, parse_watch(kind)?),
+            ["unwatch", addr] => Command::Unwatch(parse_addr(addr)?),
+            ["r"] | ["regs"] => Command::Registers,
+            ["x", addr] => Command::Hexdump(parse_addr(addr)?, HEXDUMP_LEN),
+            ["x", addr, len] => Command::Hexdump(parse_addr(addr)?, parse_count(len)?),
+            ["l"] | ["list"] => Command::List(None),
+            ["l", addr] | ["list", addr] => Command::List(Some(parse_addr(addr)?)),
+            ["screen"] => Command::Screen,
+            ["key", key] => match parse_addr(key)? {
+                key if key < 16 => Command::Key(key as u8),
+                _ => return Err(format!("Invalid key '{}', expected 0 to F", key))
+            },
+            ["h"] | ["help"] => Command::Help,
+            ["q"] | ["quit"] => Command::Quit,
+            _ => return Err(format!("Unknown command '{}', type help for the list of commands", line.trim()))
+        };
+        Ok(command)
+    }
+}
+
+fn parse_addr(word: &str) -> Result<usize, String> {
+    let digits = word.trim_start_matches("0x").trim_start_matches("0X");
+    usize::from_str_radix(digits, 16).map_err(|_| format!("Invalid address '{}'", word))
+}
+
+fn parse_count(word: &str) -> Result<usize, String> {
+    word.parse().map_err(|_| format!("Invalid count '{}'", word))
+}
+
+fn parse_watch(word: &str) -> Result<Watch, String> {
+    match word {
+        "r" => Ok(Watch { read: true, write: false }),
+        "w" => Ok(Watch { read: false, write: true }),
+        "rw" => Ok(Watch { read: true, write: true }),
+        _ => Err(format!("Invalid access '{}', expected r, w or rw", word))
+    }
+}
+
+/// Reason execution stopped
+#[derive(Debug)]
+enum Stop {
+    Breakpoint(usize),
+    OpBreakpoint(String),
+    Watchpoint { addr: usize, access: Access, pc: usize },
+
+    /// CALL stepped over by next returned
+    Returned,
+    AwaitingKey,
+
+    /// Jump to itself, running further would never end
+    Halted(usize),
+
+    /// Instructions run without any other stop
+    Limit(usize),
+    End,
+    Error(VmError)
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Breakpoint(addr) => write!(f, "Breakpoint at 0x{:03X}", addr),
+            Stop::OpBreakpoint(op) => write!(f, "Breakpoint on {}", op),
+            Stop::Watchpoint { addr, access: Access::Read, pc } =>
+                write!(f, "Watchpoint: 0x{:03X} read by the instruction at 0x{:03X}", addr, pc),
+            Stop::Watchpoint { addr, access: Access::Write, pc } =>
+                write!(f, "Watchpoint: 0x{:03X} written by the instruction at 0x{:03X}", addr, pc),
+            Stop::Returned => Ok(()),
+            Stop::AwaitingKey => write!(f, "Waiting for a key, use key <k> to press one"),
+            Stop::Halted(addr) => write!(f, "Program halted in an infinite loop at 0x{:03X}", addr),
+            Stop::Limit(n) => write!(f, "Stopped after {} instructions, continue to resume", n),
+            Stop::End => write!(f, "The program has ended"),
+            Stop::Error(err) => write!(f, "Error: {}", err)
+        }
+    }
+}
+
+/// Memory range read or written by an instruction, computed before it runs
+fn memory_access(vm: &VM, instruction: &Instruction) -> Option<(Access, Range<usize>)> {
+    let i = vm.i() as usize;
+    // One sprite per selected plane
+    let sprites = vm.planes().count_ones() as usize;
+    let range = |x: u8, y: u8| i..i + (x as isize - y as isize).unsigned_abs() + 1;
+
+    match *instruction {
+        Instruction::Draw { n: 0, .. } => Some((Access::Read, i..i + 32 * sprites)),
+        Instruction::Draw { n, .. } => Some((Access::Read, i..i + n as usize * sprites)),
+        Instruction::StoreAtIAsDecimal { .. } => Some((Access::Write, i..i + 3)),
+        Instruction::DumpToMemory { x } => Some((Access::Write, i..i + x as usize + 1)),
+        Instruction::LoadFromMemory { x } => Some((Access::Read, i..i + x as usize + 1)),
+        Instruction::SaveRange { x, y } => Some((Access::Write, range(x, y))),
+        Instruction::LoadRange { x, y } => Some((Access::Read, range(x, y))),
+        Instruction::LoadAudioPattern => Some((Access::Read, i..i + 16)),
+        _ => None
+    }
+}
+
+/// Mnemonic of the instruction as written by to_asm, the variant name when it has none
+fn mnemonic(instruction: &Instruction) -> String {
+    match instruction.to_asm().split_whitespace().next() {
+        Some(mnemonic) => mnemonic.to_owned(),
+        None => instruction.to_string()
+    }
+}
+
+/// Interactive step debugger, runs the VM without frontend
+pub struct Debugger {
+    vm: VM,
+
+    /// Instructions executed between two timers ticks
+    cycles_per_frame: usize,
+
+    /// Instructions executed since the last timers tick
+    cycles: usize,
+    breakpoints: BTreeSet<usize>,
+
+    /// Mnemonics breaking execution, in upper case
+    op_breakpoints: BTreeSet<String>,
+    watchpoints: BTreeMap<usize, Watch>,
+
+    /// Command repeated by an empty line
+    last_command: String
+}
+
+impl Debugger {
+    pub fn new(vm: VM, cycles_per_frame: usize) -> Self {
+        Debugger {
+            vm,
+            cycles_per_frame: cycles_per_frame.max(1),
+            cycles: 0,
+            breakpoints: BTreeSet::new(),
+            op_breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            last_command: String::new()
+        }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    /// Read commands from input until quit or the end of input
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{}", self.location(self.vm.pc()))?;
+        write!(out, "{}", PROMPT)?;
+        out.flush()?;
+
+        for line in input.lines() {
+            let mut line = line?;
+            if line.trim().is_empty() {
+                line = self.last_command.clone();
+            } else {
+                self.last_command = line.clone();
+            }
+
+            if !line.trim().is_empty() {
+                match line.parse() {
+                    Ok(Command::Quit) => return Ok(()),
+                    Ok(command) => self.execute(command, out)?,
+                    Err(err) => writeln!(out, "{}", err)?
+                }
+            }
+            write!(out, "{}", PROMPT)?;
+            out.flush()?;
+        }
+        writeln!(out)
+    }
+
+    fn execute<W: Write>(&mut self, command: Command, out: &mut W) -> io::Result<()> {
+        match command {
+            Command::Step(n) => {
+                for _ in 0..n {
+                    if let Err(stop) = self.step() {
+                        writeln!(out, "{}", stop)?;
+                        break;
+                    }
+                }
+                writeln!(out, "{}", self.location(self.vm.pc()))?;
+            },
+            Command::Next => {
+                let stop = self.next();
+                self.report_stop(stop, out)?;
+            },
+            Command::Continue(limit) => {
+                let stop = self.resume(None, limit);
+                self.report_stop(Some(stop), out)?;
+            },
+            Command::Break(addr) => {
+                self.breakpoints.insert(addr);
+                writeln!(out, "Breakpoint at 0x{:03X}", addr)?;
+            },
+            Command::BreakOp(op) => {
+                writeln!(out, "Breakpoint on {}", op)?;
+                self.op_breakpoints.insert(op);
+            },
+            Command::ListBreakpoints => self.list_breakpoints(out)?,
+            Command::Delete(addr) => if !self.breakpoints.remove(&addr) {
+                writeln!(out, "No breakpoint at 0x{:03X}", addr)?;
+            },
+            Command::DeleteOp(op) => if !self.op_breakpoints.remove(&op) {
+                writeln!(out, "No breakpoint on {}", op)?;
+            },
+            Command::Watch(addr, watch) => {
+                self.watchpoints.insert(addr, watch);
+                writeln!(out, "Watchpoint ({}) at 0x{:03X}", watch, addr)?;
+            },
+            Command::Unwatch(addr) => if self.watchpoints.remove(&addr).is_none() {
+                writeln!(out, "No watchpoint at 0x{:03X}", addr)?;
+            },
+            Command::Registers => for line in dump::report(&self.vm) {
+                writeln!(out, "{}", line)?;
+            },
+            Command::Hexdump(addr, len) => self.hexdump(addr, len, out)?,
+            Command::List(addr) => self.list(addr.unwrap_or_else(|| self.vm.pc()), out)?,
+            Command::Screen => dump::write_state(&self.vm, DumpFormat::Ascii, out)?,
+            Command::Key(key) => {
+                self.vm.press_key(key);
+                self.vm.push_key_event(KeyEvent::Released(key));
+            },
+            Command::Help => writeln!(out, "{}", HELP)?,
+            Command::Quit => ()
+        }
+        Ok(())
+    }
+
+    fn report_stop<W: Write>(&self, stop: Option<Stop>, out: &mut W) -> io::Result<()> {
+        match stop {
+            Some(Stop::Returned) | None => (),
+            Some(stop) => writeln!(out, "{}", stop)?
+        }
+        writeln!(out, "{}", self.location(self.vm.pc()))
+    }
+
+    /// Execute one instruction, ticking the timers every cycles_per_frame instructions
+    fn step(&mut self) -> Result<(), Stop> {
+        if !self.vm.run() {
+            return Err(Stop::End);
+        }
+
+        let pc = self.vm.pc();
+        let access = memory_access(&self.vm, &self.instruction_at(pc));
+        self.vm.execute_next().map_err(Stop::Error)?;
+
+        self.cycles += 1;
+        if self.cycles == self.cycles_per_frame {
+            self.cycles = 0;
+            self.vm.tick_timers();
+        }
+
+        if !self.vm.run() {
+            return Err(Stop::End);
+        }
+        if self.vm.is_awaiting_key() {
+            return Err(Stop::AwaitingKey);
+        }
+        if let Some((access, range)) = access {
+            if let Some(addr) = range.into_iter().find(|addr| self.is_watched(*addr, access)) {
+                return Err(Stop::Watchpoint { addr, access, pc });
+            }
+        }
+        Ok(())
+    }
+
+    /// Step, running a subroutine call until it returns
+    fn next(&mut self) -> Option<Stop> {
+        let pc = self.vm.pc();
+        match self.instruction_at(pc) {
+            Instruction::CallSubroutine { .. } => Some(self.resume(Some((pc + 2, self.vm.stack().len())), RUN_LIMIT)),
+            _ => self.step().err()
+        }
+    }
+
+    /// Run until a stop, or until PC reaches the address at the given stack depth,
+    /// at most limit instructions. The instruction at PC is always executed to leave a breakpoint
+    fn resume(&mut self, until: Option<(usize, usize)>, limit: usize) -> Stop {
+        let mut first = true;
+        for _ in 0..limit {
+            let pc = self.vm.pc();
+            let instruction = self.instruction_at(pc);
+
+            if !first {
+                if until == Some((pc, self.vm.stack().len())) {
+                    return Stop::Returned;
+                }
+                if self.breakpoints.contains(&pc) {
+                    return Stop::Breakpoint(pc);
+                }
+                let op = mnemonic(&instruction);
+                if self.op_breakpoints.contains(&op) {
+                    return Stop::OpBreakpoint(op);
+                }
+            }
+            if let Instruction::Goto { addr } = instruction {
+                if addr as usize == pc && self.vm.run() {
+                    return Stop::Halted(pc);
+                }
+            }
+
+            if let Err(stop) = self.step() {
+                return stop;
+            }
+            first = false;
+        }
+        Stop::Limit(limit)
+    }
+
+    fn is_watched(&self, addr: usize, access: Access) -> bool {
+        self.watchpoints.get(&addr).is_some_and(|watch| watch.matches(access))
+    }
+
+    fn instruction_at(&self, addr: usize) -> Instruction {
+        match self.vm.memory().get(addr..) {
+            Some(bytes) => Instruction::decode(bytes),
+            None => Instruction::UnknownInstruction
+        }
+    }
+
+    /// Address, opcode and mnemonic of the instruction at addr
+    fn location(&self, addr: usize) -> String {
+        let memory = self.vm.memory();
+        let instruction = self.instruction_at(addr);
+        let asm = match instruction.to_asm().as_str() {
+            "" => instruction.to_string(),
+            asm => asm.to_owned()
+        };
+        match (memory.get(addr), memory.get(addr + 1)) {
+            (Some(b1), Some(b2)) => format!("0x{:03X}  {:02X}{:02X}  {}", addr, b1, b2, asm),
+            _ => format!("0x{:03X}  out of memory", addr)
+        }
+    }
+
+    fn list<W: Write>(&self, addr: usize, out: &mut W) -> io::Result<()> {
+        let start = addr.saturating_sub(2 * LIST_CONTEXT);
+        let end = (addr + 2 * LIST_CONTEXT).min(self.vm.memory().len() - 2);
+        for x in (start..=end).step_by(2) {
+            let pc = if x == self.vm.pc() { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&x) { "*" } else { " " };
+            writeln!(out, "{}{} {}", pc, breakpoint, self.location(x))?;
+        }
+        Ok(())
+    }
+
+    fn hexdump<W: Write>(&self, addr: usize, len: usize, out: &mut W) -> io::Result<()> {
+        let memory = self.vm.memory();
+        let end = addr.saturating_add(len).min(memory.len());
+        if addr >= end {
+            return writeln!(out, "Address 0x{:03X} is out of memory", addr);
+        }
+        for (row, bytes) in memory[addr..end].chunks(16).enumerate() {
+            let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            writeln!(out, "0x{:03X}: {}", addr + row * 16, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+
+    fn list_breakpoints<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if self.breakpoints.is_empty() && self.op_breakpoints.is_empty() && self.watchpoints.is_empty() {
+            return writeln!(out, "No breakpoints or watchpoints");
+        }
+        for addr in &self.breakpoints {
+            writeln!(out, "Breakpoint at 0x{:03X}", addr)?;
+        }
+        for op in &self.op_breakpoints {
+            writeln!(out, "Breakpoint on {}", op)?;
+        }
+        for (addr, watch) in &self.watchpoints {
+            writeln!(out, "Watchpoint ({}) at 0x{:03X}", watch, addr)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    // 0x200 LD V0, 0x07
+    // 0x202 CALL 0x20A
+    // 0x204 LD B, V0 (I = 0)
+    // 0x206 DRW V0, V0, 5
+    // 0x208 JP 0x208
+    // 0x20A ADD V0, 1
+    // 0x20C RET
+    const ROM: [u8; 14] = [
+        0x60, 0x07, 0x22, 0x0A, 0xF0, 0x33, 0xD0, 0x05, 0x12, 0x08, 0x70, 0x01, 0x00, 0xEE
+    ];
+
+    fn debugger() -> Debugger {
+        Debugger::new(VM::try_from(&ROM[..]).unwrap(), 10)
+    }
+
+    fn session(script: &str) -> (Debugger, String) {
+        let mut debugger = debugger();
+        let mut out = vec![];
+        debugger.repl(script.as_bytes(), &mut out).unwrap();
+        (debugger, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("s".parse(), Ok(Command::Step(1)));
+        assert_eq!("step 12".parse(), Ok(Command::Step(12)));
+        assert_eq!("b 0x20A".parse(), Ok(Command::Break(0x20A)));
+        assert_eq!("break op drw".parse(), Ok(Command::BreakOp("DRW".to_owned())));
+        assert_eq!("w 300 w".parse(), Ok(Command::Watch(0x300, Watch { read: false, write: true })));
+        assert_eq!("x 200 4".parse(), Ok(Command::Hexdump(0x200, 4)));
+        assert_eq!("c".parse(), Ok(Command::Continue(RUN_LIMIT)));
+        assert_eq!("continue 50".parse(), Ok(Command::Continue(50)));
+        assert_eq!("key f".parse(), Ok(Command::Key(0xF)));
+        assert!("key 10".parse::<Command>().is_err());
+        assert!("b zz".parse::<Command>().is_err());
+        assert!("jump".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_step_over() {
+        let mut debugger = debugger();
+        debugger.step().unwrap();
+        assert!(debugger.next().is_some());
+        assert_eq!(debugger.vm().pc(), 0x204);
+        assert_eq!(debugger.vm().registers()[0], 0x08);
+        assert!(debugger.vm().stack().is_empty());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger();
+        debugger.breakpoints.insert(0x20C);
+        match debugger.resume(None, RUN_LIMIT) {
+            Stop::Breakpoint(0x20C) => (),
+            stop => panic!("Unexpected stop {:?}", stop)
+        }
+
+        debugger.breakpoints.clear();
+        debugger.op_breakpoints.insert("DRW".to_owned());
+        match debugger.resume(None, RUN_LIMIT) {
+            Stop::OpBreakpoint(ref op) if op == "DRW" => assert_eq!(debugger.vm().pc(), 0x206),
+            stop => panic!("Unexpected stop {:?}", stop)
+        }
+
+        match debugger.resume(None, RUN_LIMIT) {
+            Stop::Halted(0x208) => (),
+            stop => panic!("Unexpected stop {:?}", stop)
+        }
+    }
+
+    #[test]
+    fn test_run_limit() {
+        // LD V1, DT; SE V1, 5; JP 0x200: polls the delay timer forever
+        let vm = VM::try_from(&[0xF1, 0x07, 0x31, 0x05, 0x12, 0x00][..]).unwrap();
+        let mut debugger = Debugger::new(vm, 10);
+        assert!(matches!(debugger.resume(None, 100), Stop::Limit(100)));
+
+        let mut out = vec![];
+        debugger.repl("c 30\n".as_bytes(), &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("Stopped after 30 instructions"));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debugger();
+        debugger.watchpoints.insert(0x001, Watch { read: false, write: true });
+        match debugger.resume(None, RUN_LIMIT) {
+            Stop::Watchpoint { addr: 0x001, access: Access::Write, pc: 0x204 } => (),
+            stop => panic!("Unexpected stop {:?}", stop)
+        }
+        assert_eq!(&debugger.vm().memory()[..3], &[0, 0, 8]);
+
+        // DRW reads I..I+5 but the watchpoint only triggers on writes
+        assert!(matches!(debugger.resume(None, RUN_LIMIT), Stop::Halted(0x208)));
+    }
+
+    #[test]
+    fn test_repl() {
+        let (debugger, out) = session("b 20a\nc\nr\n\nn\nn\nquit\nstep\n");
+        assert_eq!(debugger.vm().pc(), 0x204);
+
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "0x200  6007  LD V0, 7");
+        assert!(lines.contains(&"(chip8) Breakpoint at 0x20A"));
+        assert!(lines.contains(&"0x20A  7001  ADD V0, 1"));
+        assert_eq!(lines.iter().filter(|line| line.ends_with("PC: 0x20A")).count(), 2);
+    }
+}
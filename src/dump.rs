@@ -0,0 +1,125 @@
+use std::io::{ self, Write };
+use std::str::FromStr;
+
+use crate::vm::VM;
+
+/// Output format of the framebuffer in a state dump
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    /// Text report followed by the display drawn with '#' and '.'
+    Ascii,
+
+    /// Plain PBM (P1) image, the text report is stored as comments
+    Pbm
+}
+
+impl FromStr for DumpFormat {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "ascii" => Ok(DumpFormat::Ascii),
+            "pbm" => Ok(DumpFormat::Pbm),
+            _ => Err(())
+        }
+    }
+}
+
+/// Write registers, I, PC, stack, timers and display of the VM
+pub fn write_state<W: Write>(vm: &VM, format: DumpFormat, out: &mut W) -> io::Result<()> {
+    let prefix = match format {
+        DumpFormat::Ascii => "",
+        DumpFormat::Pbm => "# "
+    };
+
+    if format == DumpFormat::Pbm {
+        writeln!(out, "P1")?;
+    }
+    for line in report(vm) {
+        writeln!(out, "{}{}", prefix, line)?;
+    }
+
+    match format {
+        DumpFormat::Ascii => {
+            writeln!(out)?;
+            for row in vm.display().rows() {
+                let line: String = row.iter().map(|p| if *p != 0 { '#' } else { '.' }).collect();
+                writeln!(out, "{}", line)?;
+            }
+        },
+        DumpFormat::Pbm => {
+            writeln!(out, "{} {}", vm.display().width(), vm.display().height())?;
+            for row in vm.display().rows() {
+                let line: Vec<&str> = row.iter().map(|p| if *p != 0 { "1" } else { "0" }).collect();
+                writeln!(out, "{}", line.join(" "))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Text report of PC, I, registers, stack and timers
+pub(crate) fn report(vm: &VM) -> Vec<String> {
+    let regs: Vec<String> = vm.registers()
+        .iter()
+        .enumerate()
+        .map(|(x, v)| format!("V{:X}=0x{:02X}", x, v))
+        .collect();
+    let stack: Vec<String> = vm.stack().iter().map(|addr| format!("0x{:03X}", addr)).collect();
+
+    vec![
+        format!("PC: 0x{:03X}", vm.pc()),
+        format!("I: 0x{:03X}", vm.i()),
+        format!("Registers: {}", regs.join(" ")),
+        format!("Stack: [{}]", stack.join(", ")),
+        format!("DT: {} ST: {}", vm.delay_timer(), vm.sound_timer())
+    ]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::vm::DISPLAY_HEIGHT;
+
+    fn dump(format: DumpFormat) -> String {
+        // LD V0, 0x2A; LD I, 0x050; DRW V1, V1, 5 (glyph 0 at the top left corner)
+        let rom = [0x60, 0x2A, 0xA0, 0x50, 0xD1, 0x15];
+        let mut vm = VM::try_from(&rom[..]).unwrap();
+        for _ in 0..3 {
+            vm.execute_next().unwrap();
+        }
+
+        let mut out = vec![];
+        write_state(&vm, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_ascii_dump() {
+        let out = dump(DumpFormat::Ascii);
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines[0], "PC: 0x206");
+        assert_eq!(lines[1], "I: 0x050");
+        assert!(lines[2].starts_with("Registers: V0=0x2A V1=0x00"));
+        assert_eq!(lines[3], "Stack: []");
+        assert_eq!(lines.len(), 6 + DISPLAY_HEIGHT);
+        assert!(lines[6].starts_with("####...."));
+        assert!(lines[7].starts_with("#..#...."));
+    }
+
+    #[test]
+    fn test_pbm_dump() {
+        let out = dump(DumpFormat::Pbm);
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines[0], "P1");
+        assert_eq!(lines[1], "# PC: 0x206");
+        assert_eq!(lines[6], "64 32");
+        assert!(lines[7].starts_with("1 1 1 1 0"));
+        assert_eq!(lines.len(), 7 + DISPLAY_HEIGHT);
+    }
+}
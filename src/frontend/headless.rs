@@ -0,0 +1,41 @@
+use std::io;
+
+use crate::frontend::Frontend;
+use crate::vm::{ KeyEvent, Framebuffer };
+
+/// Frontend without display, input nor audio
+#[derive(Debug, Default)]
+pub struct Headless {
+
+    /// Number of frames presented
+    frames: u64
+}
+
+impl Headless {
+    pub fn new() -> Self {
+        Headless::default()
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+impl Frontend for Headless {
+    fn present(&mut self, _display: &Framebuffer) -> io::Result<()> {
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn poll_input(&mut self) -> io::Result<Vec<KeyEvent>> {
+        Ok(vec![])
+    }
+
+    fn start_beep(&mut self) {}
+
+    fn stop_beep(&mut self) {}
+
+    fn should_quit(&self) -> bool {
+        false
+    }
+}
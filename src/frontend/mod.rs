@@ -0,0 +1,62 @@
+use std::io;
+
+use crate::vm::{ KeyEvent, Framebuffer };
+
+pub mod terminal;
+pub mod headless;
+
+/// Requests from the user to the machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Save the VM state in the numbered slot
+    SaveState(u8),
+
+    /// Restore the VM state from the numbered slot
+    LoadState(u8),
+
+    /// Go back in time by the number of frames
+    Rewind(u32),
+
+    TogglePause,
+
+    /// Run a single frame while paused
+    AdvanceFrame,
+
+    /// Run as fast as possible or at the clock speed
+    ToggleTurbo,
+
+    /// Step the clock speed up or down, see clock::SPEED_STEPS
+    SpeedUp,
+    SlowDown,
+
+    /// Show the speed and the achieved instructions per second
+    ShowSpeed
+}
+
+/// Display, input and audio of a machine, the VM core being driven by a Machine
+pub trait Frontend {
+
+    /// Show the display, called once per frame, display.dirty() being the area changed
+    /// since the previous call
+    fn present(&mut self, display: &Framebuffer) -> io::Result<()>;
+
+    /// Keypad events received since the last call
+    fn poll_input(&mut self) -> io::Result<Vec<KeyEvent>>;
+
+    /// Sound timer became active
+    fn start_beep(&mut self);
+
+    /// Sound timer expired
+    fn stop_beep(&mut self);
+
+    /// Whether the user asked to stop the machine
+    fn should_quit(&self) -> bool;
+
+    /// Commands received since the last call, polled after the input
+    fn poll_commands(&mut self) -> Vec<Command> {
+        vec![]
+    }
+
+    /// Show a status message to the user
+    fn notify(&mut self, _message: &str) {}
+}
@@ -0,0 +1,296 @@
+use std::io::{ self, Read, Write };
+use std::mem;
+
+use crate::frontend::{ Frontend, Command };
+use crate::vm::{ KeyEvent, Keymap, Framebuffer, TIMER_FREQUENCY };
+
+/// Terminals only report key presses (and their auto repeat), a key is
+/// considered released when it has not been seen for this many frames.
+/// Longer than the usual auto repeat delays (250 to 660 ms) so that held keys
+/// are not released before their first repeat
+const KEY_HOLD_FRAMES: u32 = 45;
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x7F;
+const CTRL_H: u8 = 0x08;
+const ESCAPE: u8 = 0x1B;
+
+/// Stdin in raw, non blocking mode, restored when dropped
+struct RawMode {
+    original: libc::termios
+}
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        unsafe {
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            raw.c_oflag |= libc::OPOST;
+            raw.c_cc[libc::VMIN] = 0;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { original })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Key releases emulated from the key presses seen in each frame
+#[derive(Debug, Default)]
+struct HeldKeys {
+
+    /// Frames left before each key is released, 0 when the key is up
+    frames: [u32; 16]
+}
+
+impl HeldKeys {
+
+    /// Events of a frame in which keys were seen, in order and possibly repeated
+    fn update(&mut self, keys: &[u8]) -> Vec<KeyEvent> {
+        let mut events = vec![];
+        for &key in keys {
+            if self.frames[key as usize] == 0 {
+                events.push(KeyEvent::Pressed(key));
+            }
+            self.frames[key as usize] = KEY_HOLD_FRAMES;
+        }
+
+        for (key, frames) in self.frames.iter_mut().enumerate() {
+            if *frames > 0 {
+                *frames -= 1;
+                if *frames == 0 {
+                    events.push(KeyEvent::Released(key as u8));
+                }
+            }
+        }
+        events
+    }
+}
+
+/// ANSI terminal frontend, two pixel rows per character using half blocks
+pub struct Terminal {
+    keymap: Keymap,
+
+    /// Size in half block characters of a pixel
+    scale: usize,
+
+    held: HeldKeys,
+
+    /// Lines currently displayed, used to only redraw changed rows
+    lines: Vec<String>,
+
+    /// Esc or Ctrl-C pressed
+    quit: bool,
+
+    /// Selected save state slot
+    slot: u8,
+    commands: Vec<Command>,
+
+    /// Kept for the terminal to be restored on drop
+    _raw_mode: RawMode
+}
+
+impl Terminal {
+    pub fn new(keymap: Keymap, scale: usize) -> io::Result<Self> {
+        let raw_mode = RawMode::enable()?;
+
+        // Alternate screen, hidden cursor
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        io::stdout().flush()?;
+        Ok(Terminal {
+            keymap,
+            scale: scale.max(1),
+            held: HeldKeys::default(),
+            lines: vec![],
+            quit: false,
+            slot: 0,
+            commands: vec![],
+            _raw_mode: raw_mode
+        })
+    }
+}
+
+impl Frontend for Terminal {
+    fn present(&mut self, display: &Framebuffer) -> io::Result<()> {
+        if display.dirty().is_none() && !self.lines.is_empty() {
+            return Ok(());
+        }
+        let lines = render(display, self.scale);
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+
+        // The resolution changed, previous lines may be longer than the new ones
+        if lines.len() != self.lines.len() {
+            write!(out, "\x1b[2J")?;
+            self.lines.clear();
+        }
+
+        for (row, line) in lines.iter().enumerate() {
+            if self.lines.get(row) != Some(line) {
+                write!(out, "\x1b[{};1H{}", row + 1, line)?;
+            }
+        }
+        self.lines = lines;
+        out.flush()
+    }
+
+    /// Called once per frame, key releases are emulated from the key repeat
+    fn poll_input(&mut self) -> io::Result<Vec<KeyEvent>> {
+        let mut buf = [0; 64];
+        let len = io::stdin().read(&mut buf)?;
+        let mut bytes = buf[..len].iter();
+        let mut keys = vec![];
+
+        while let Some(&byte) = bytes.next() {
+            match byte {
+                CTRL_C => self.quit = true,
+
+                // Lone escape quits, escape sequences (arrows, function keys) are skipped
+                ESCAPE if bytes.len() == 0 => self.quit = true,
+                ESCAPE => {
+                    bytes.next();
+                    for b in bytes.by_ref() {
+                        if b.is_ascii_alphabetic() || *b == b'~' {
+                            break;
+                        }
+                    }
+                },
+                _ => match self.keymap.get(byte as char) {
+                    Some(key) => keys.push(key),
+                    None => self.command_key(byte)
+                }
+            }
+        }
+        Ok(self.held.update(&keys))
+    }
+
+    fn poll_commands(&mut self) -> Vec<Command> {
+        self.commands.drain(..).collect()
+    }
+
+    /// Message on the line below the display
+    fn notify(&mut self, message: &str) {
+        print!("\x1b[{};1H\x1b[2K{}", self.lines.len() + 2, message);
+        let _ = io::stdout().flush();
+    }
+
+    /// Terminal bell
+    fn start_beep(&mut self) {
+        print!("\x07");
+        let _ = io::stdout().flush();
+    }
+
+    fn stop_beep(&mut self) {}
+
+    fn should_quit(&self) -> bool {
+        self.quit
+    }
+}
+
+impl Terminal {
+
+    /// Save state keys: [ and ] select the slot, o saves and p loads.
+    /// Backspace rewinds one second. Clock keys: space pauses, n advances one frame,
+    /// t toggles turbo, - and + change the speed and i shows it
+    fn command_key(&mut self, byte: u8) {
+        match byte {
+            BACKSPACE | CTRL_H => self.commands.push(Command::Rewind(TIMER_FREQUENCY)),
+            b'[' | b']' => {
+                self.slot = if byte == b'[' { (self.slot + 9) % 10 } else { (self.slot + 1) % 10 };
+                let message = format!("Slot {}", self.slot);
+                self.notify(&message);
+            },
+            b'o' => self.commands.push(Command::SaveState(self.slot)),
+            b'p' => self.commands.push(Command::LoadState(self.slot)),
+            b' ' => self.commands.push(Command::TogglePause),
+            b'n' => self.commands.push(Command::AdvanceFrame),
+            b't' => self.commands.push(Command::ToggleTurbo),
+            b'-' => self.commands.push(Command::SlowDown),
+            b'+' | b'=' => self.commands.push(Command::SpeedUp),
+            b'i' => self.commands.push(Command::ShowSpeed),
+            _ => ()
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Render the display with half blocks, each pixel being scale characters wide
+/// and scale half characters high
+fn render(display: &Framebuffer, scale: usize) -> Vec<String> {
+    let height = display.height() * scale;
+    let pixel = |x: usize, y: usize| display.get(x / scale, y / scale);
+
+    (0..height).step_by(2).map(|y| {
+        (0..display.width() * scale).map(|x| {
+            match (pixel(x, y), pixel(x, y + 1)) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' '
+            }
+        }).collect()
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{ DISPLAY_WIDTH, DISPLAY_HEIGHT };
+
+    #[test]
+    fn test_render_half_blocks() {
+        let mut display = Framebuffer::default();
+        display.set(0, 0, true);
+        display.set(1, 0, true);
+        display.set(1, 1, true);
+        display.set(2, 3, true);
+
+        let lines = render(&display, 1);
+        assert_eq!(lines.len(), DISPLAY_HEIGHT / 2);
+        assert!(lines[0].starts_with("▀█ "));
+        assert!(lines[1].starts_with("  ▄ "));
+        assert_eq!(lines[0].chars().count(), DISPLAY_WIDTH);
+
+        let lines = render(&display, 2);
+        assert_eq!(lines.len(), DISPLAY_HEIGHT);
+        assert!(lines[0].starts_with("████ "));
+    }
+
+    #[test]
+    fn test_held_keys() {
+        let mut held = HeldKeys::default();
+        assert_eq!(held.update(&[0x5, 0x5]), vec![KeyEvent::Pressed(0x5)]);
+
+        // Auto repeat starting after 500 ms keeps the key down
+        for _ in 0..30 {
+            assert_eq!(held.update(&[]), vec![]);
+        }
+        assert_eq!(held.update(&[0x5]), vec![]);
+
+        for _ in 0..KEY_HOLD_FRAMES - 2 {
+            assert_eq!(held.update(&[]), vec![]);
+        }
+        assert_eq!(held.update(&[0xA]), vec![KeyEvent::Pressed(0xA), KeyEvent::Released(0x5)]);
+    }
+}
@@ -10,13 +10,10 @@
  *
  **/
 
-use std::cmp::PartialEq;
-use std::fmt;
-use std::string::ToString;
 use strum_macros::{ Display };
 
 // todo documentation
-#[derive(Display)]
+#[derive(Display, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
 
     /// Call program at address NNN
@@ -28,6 +25,24 @@ pub enum Instruction {
     /// Return from a subroutine
     Return,
 
+    /// Scroll the display down by N pixels (SCHIP)
+    ScrollDown { n: u8 },
+
+    /// Scroll the display right by 4 pixels (SCHIP)
+    ScrollRight,
+
+    /// Scroll the display left by 4 pixels (SCHIP)
+    ScrollLeft,
+
+    /// Exit the interpreter (SCHIP)
+    Exit,
+
+    /// Switch to the 64x32 low resolution (SCHIP)
+    LowResolution,
+
+    /// Switch to the 128x64 high resolution (SCHIP)
+    HighResolution,
+
     /// Jump to address NNN
     Goto { addr: u16 },
 
@@ -43,6 +58,12 @@ pub enum Instruction {
     /// Skips the next instruction if VX equal VY
     SkipEqualReg { x: u8, y: u8 },
 
+    /// Store VX to VY in memory at I, I is left unchanged (XO-CHIP)
+    SaveRange { x: u8, y: u8 },
+
+    /// Load VX to VY from memory at I, I is left unchanged (XO-CHIP)
+    LoadRange { x: u8, y: u8 },
+
     /// Sets VX to NN
     SetFromU8 { x: u8, value: u8 },
 
@@ -64,17 +85,19 @@ pub enum Instruction {
     /// Adds VY to VX, VF is set to 1 when there's a carry and to 0 when there isn't
     AddReg { x: u8, y: u8 },
 
-    /// VY is substracted from VX, VF is set to 1 when there's a carry and to 0 when there isn't
+    /// VY is substracted from VX, VF is set to 0 when there's a borrow and to 1 when there isn't
     SubReg { x: u8, y: u8 },
 
-    /// Stores the least significant bit of VX in VF and then shifts VX to the right by 1
-    ShiftRight { x: u8 },
+    /// Stores the least significant bit of VX in VF and then shifts VX to the right by 1,
+    /// on the COSMAC VIP VY is shifted and stored in VX instead
+    ShiftRight { x: u8, y: u8 },
 
-    /// Sets VX to VY minus VX, VF is set to 1 when there's a carry and to 0 when there isn't
+    /// Sets VX to VY minus VX, VF is set to 0 when there's a borrow and to 1 when there isn't
     RevSubReg { x: u8, y: u8 },
 
-    /// Stores the most significant bit of VX in VF and then shifts VX to the left by 1
-    ShiftLeft { x: u8 },
+    /// Stores the most significant bit of VX in VF and then shifts VX to the left by 1,
+    /// on the COSMAC VIP VY is shifted and stored in VX instead
+    ShiftLeft { x: u8, y: u8 },
 
     /// Skips the next instruction if VX equal VY
     SkipNotEqualReg { x: u8, y: u8 },
@@ -82,13 +105,26 @@ pub enum Instruction {
     /// Sets I to the address NNN
     StoreAddress { addr: u16 },
 
+    /// Sets I to the 16 bits address NNNN stored in the word following the opcode (XO-CHIP).
+    /// The opcode alone decodes with addr set to 0, see Instruction::decode
+    LongStoreAddress { addr: u16 },
+
+    /// Select the bitplanes N drawn, cleared and scrolled (XO-CHIP)
+    SelectPlanes { n: u8 },
+
+    /// Load the 16 bytes audio pattern from memory at I (XO-CHIP)
+    LoadAudioPattern,
+
+    /// Sets the audio pattern playback pitch to VX (XO-CHIP)
+    SetPitch { x: u8 },
+
     /// Jumps to the address NNN
     JumpToAddress { addr: u16 },
 
     /// Set VX to bitwise and operation on random number and NN
     Rand { x: u8, value: u8 },
 
-    /// Draw a sprite at (VX, VY) of width 8 and height N
+    /// Draw a sprite at (VX, VY) of width 8 and height N, N = 0 draws a 16x16 sprite (SCHIP)
     Draw  { x: u8, y: u8, n: u8 },
 
     /// Skips next instruction if the key stored in VX is pressed
@@ -115,6 +151,9 @@ pub enum Instruction {
     /// Sets I to the location of the sprite for the character in VX
     SetIToSpriteAddress { x: u8 },
 
+    /// Sets I to the location of the 8x10 sprite for the character in VX (SCHIP)
+    SetIToBigSpriteAddress { x: u8 },
+
     /// Store decimal representation of VX at I in memory
     StoreAtIAsDecimal { x: u8 },
 
@@ -124,6 +163,12 @@ pub enum Instruction {
     /// Load from memory at I
     LoadFromMemory { x: u8 },
 
+    /// Store V0 to VX in the HP48 RPL user flags (SCHIP)
+    StoreFlags { x: u8 },
+
+    /// Load V0 to VX from the HP48 RPL user flags (SCHIP)
+    LoadFlags { x: u8 },
+
     /// End of program
     EndOfProgram,
 
@@ -145,12 +190,20 @@ impl From<(u8, u8)> for Instruction {
             (0x0, 0x0, 0x0, 0x0) => Instruction::EndOfProgram,
             (0x0, 0x0, 0xE, 0x0) => Instruction::Clear,
             (0x0, 0x0, 0xE, 0xE) => Instruction::Return,
+            (0x0, 0x0, 0xC, n) => Instruction::ScrollDown { n },
+            (0x0, 0x0, 0xF, 0xB) => Instruction::ScrollRight,
+            (0x0, 0x0, 0xF, 0xC) => Instruction::ScrollLeft,
+            (0x0, 0x0, 0xF, 0xD) => Instruction::Exit,
+            (0x0, 0x0, 0xF, 0xE) => Instruction::LowResolution,
+            (0x0, 0x0, 0xF, 0xF) => Instruction::HighResolution,
             (0x0, n1, n2, n3) => Instruction::CallProgram { addr: Instruction::address_from(n1, n2, n3) },
             (0x1, n1, n2, n3) => Instruction::Goto { addr: Instruction::address_from(n1, n2, n3) },
             (0x2, n1, n2, n3) => Instruction::CallSubroutine { addr: Instruction::address_from(n1, n2, n3) },
             (0x3, x, n1, n2) => Instruction::SkipEqualU8 { x, value: Instruction::value_from(n1, n2) },
             (0x4, x, n1, n2) => Instruction::SkipNotEqualU8 { x, value: Instruction::value_from(n1, n2) },
             (0x5, x, y, 0x0) => Instruction::SkipEqualReg { x, y },
+            (0x5, x, y, 0x2) => Instruction::SaveRange { x, y },
+            (0x5, x, y, 0x3) => Instruction::LoadRange { x, y },
             (0x6, x, n1, n2) => Instruction::SetFromU8 { x, value: Instruction::value_from(n1, n2) },
             (0x7, x, n1, n2) => Instruction::AddU8 { x, value: Instruction::value_from(n1, n2) },
             (0x8, x, y, 0x0) => Instruction::SetFromReg { x, y },
@@ -159,9 +212,9 @@ impl From<(u8, u8)> for Instruction {
             (0x8, x, y, 0x3) => Instruction::XorReg { x, y },
             (0x8, x, y, 0x4) => Instruction::AddReg { x, y },
             (0x8, x, y, 0x5) => Instruction::SubReg { x, y },
-            (0x8, x, _, 0x6) => Instruction::ShiftRight { x },
+            (0x8, x, y, 0x6) => Instruction::ShiftRight { x, y },
             (0x8, x, y, 0x7) => Instruction::RevSubReg { x, y },
-            (0x8, x, _, 0xE) => Instruction::ShiftLeft { x },
+            (0x8, x, y, 0xE) => Instruction::ShiftLeft { x, y },
             (0x9, x, y, 0x0) => Instruction::SkipNotEqualReg { x, y },
             (0xA, n1, n2, n3) => Instruction::StoreAddress { addr: Instruction::address_from(n1, n2, n3) },
             (0xB, n1, n2, n3) => Instruction::JumpToAddress { addr: Instruction::address_from(n1, n2, n3) },
@@ -169,15 +222,22 @@ impl From<(u8, u8)> for Instruction {
             (0xD, x, y, n) => Instruction::Draw { x, y, n },
             (0xE, x, 0x9, 0xE) => Instruction::SkipIfKeyPressed { x },
             (0xE, x, 0xA, 0x1) => Instruction::SkipIfNotKeyPressed { x },
+            (0xF, 0x0, 0x0, 0x0) => Instruction::LongStoreAddress { addr: 0 },
+            (0xF, n, 0x0, 0x1) => Instruction::SelectPlanes { n },
+            (0xF, 0x0, 0x0, 0x2) => Instruction::LoadAudioPattern,
             (0xF, x, 0x0, 0x7) => Instruction::SetFromDelayTimer { x },
             (0xF, x, 0x0, 0xA) => Instruction::AwaitKeyPressed { x },
             (0xF, x, 0x1, 0x5) => Instruction::SetDelayTimer { x },
             (0xF, x, 0x1, 0x8) => Instruction::SetSoundTimer { x },
             (0xF, x, 0x1, 0xE) => Instruction::AddToI { x },
             (0xF, x, 0x2, 0x9) => Instruction::SetIToSpriteAddress { x },
+            (0xF, x, 0x3, 0x0) => Instruction::SetIToBigSpriteAddress { x },
             (0xF, x, 0x3, 0x3) => Instruction::StoreAtIAsDecimal { x },
+            (0xF, x, 0x3, 0xA) => Instruction::SetPitch { x },
             (0xF, x, 0x5, 0x5) => Instruction::DumpToMemory { x },
             (0xF, x, 0x6, 0x5) => Instruction::LoadFromMemory { x },
+            (0xF, x, 0x7, 0x5) => Instruction::StoreFlags { x },
+            (0xF, x, 0x8, 0x5) => Instruction::LoadFlags { x },
             _ => Instruction::UnknownInstruction
         }
     }
@@ -194,31 +254,25 @@ impl From<u16> for Instruction {
 }
 
 
-impl PartialEq for Instruction {
-    fn eq(&self, other: &Self) -> bool {
-        self.to_string() == other.to_string()
-    }
-}
-
-
-impl fmt::Debug for Instruction {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self)
-    }
-}
-
-
 impl Instruction {
-    pub fn to_asm(&self) -> String {
+    pub fn to_asm(self) -> String {
         match self {
             Instruction::Clear => "CLS".to_owned(),
             Instruction::Return => "RET".to_owned(),
+            Instruction::ScrollDown { n } => format!("SCD {}", n),
+            Instruction::ScrollRight => "SCR".to_owned(),
+            Instruction::ScrollLeft => "SCL".to_owned(),
+            Instruction::Exit => "EXIT".to_owned(),
+            Instruction::LowResolution => "LOW".to_owned(),
+            Instruction::HighResolution => "HIGH".to_owned(),
             Instruction::Goto { addr } => format!("JP 0x{:X}", addr),
             Instruction::CallProgram { addr } => format!("SYS 0x{:X}", addr),
             Instruction::CallSubroutine { addr } => format!("CALL 0x{:X}", addr),
             Instruction::SkipEqualU8 { x, value } => format!("SE V{}, {}", x, value),
             Instruction::SkipNotEqualU8 { x, value } => format!("SNE V{}, {}", x, value),
             Instruction::SkipEqualReg { x, y } => format!("SE V{}, V{}", x, y),
+            Instruction::SaveRange { x, y } => format!("SAVE V{}, V{}", x, y),
+            Instruction::LoadRange { x, y } => format!("LOAD V{}, V{}", x, y),
             Instruction::SetFromU8 { x, value } => format!("LD V{}, {}", x, value),
             Instruction::AddU8 { x, value } => format!("ADD V{}, {}", x, value),
             Instruction::SetFromReg { x, y } => format!("LD V{}, V{}", x, y),
@@ -228,10 +282,14 @@ impl Instruction {
             Instruction::AddReg { x, y } => format!("ADD V{}, V{}", x, y),
             Instruction::SubReg { x, y } => format!("SUB V{}, V{}", x, y),
             Instruction::RevSubReg { x, y } => format!("SUBN V{}, V{}", x, y),
-            Instruction::ShiftRight { x } => format!("SHR V{}", x),
-            Instruction::ShiftLeft { x } => format!("SHL V{}", x),
+            Instruction::ShiftRight { x, y } => format!("SHR V{}, V{}", x, y),
+            Instruction::ShiftLeft { x, y } => format!("SHL V{}, V{}", x, y),
             Instruction::SkipNotEqualReg { x, y } => format!("SNE V{}, V{}", x, y),
             Instruction::StoreAddress { addr } => format!("LD I, 0x{:X}", addr),
+            Instruction::LongStoreAddress { addr } => format!("LD I, long 0x{:X}", addr),
+            Instruction::SelectPlanes { n } => format!("PLANE {}", n),
+            Instruction::LoadAudioPattern => "AUDIO".to_owned(),
+            Instruction::SetPitch { x } => format!("PITCH V{}", x),
             Instruction::JumpToAddress { addr } => format!("JP V0, 0x{:X}", addr),
             Instruction::Rand { x, value } => format!("RND V{}, {}", x, value),
             Instruction::Draw { x, y, n } => format!("DRW V{}, V{}, {}", x, y, n),
@@ -243,13 +301,121 @@ impl Instruction {
             Instruction::AwaitKeyPressed { x } => format!("LD V{}, K", x),
             Instruction::AddToI { x } => format!("ADD I, V{}", x),
             Instruction::SetIToSpriteAddress { x } => format!("LD F, V{}", x),
+            Instruction::SetIToBigSpriteAddress { x } => format!("LD HF, V{}", x),
             Instruction::StoreAtIAsDecimal { x } => format!("LD B, V{}", x),
             Instruction::DumpToMemory { x } => format!("LD I, V{}", x),
             Instruction::LoadFromMemory { x } => format!("LD V{}, I", x),
+            Instruction::StoreFlags { x } => format!("LD R, V{}", x),
+            Instruction::LoadFlags { x } => format!("LD V{}, R", x),
             _ => "".to_owned()
         }
     }
 
+    /// Decode the instruction at the start of bytes, including the address word of F000 NNNN
+    pub fn decode(bytes: &[u8]) -> Instruction {
+        match bytes {
+            [b1, b2, rest @ ..] => match (Instruction::from((*b1, *b2)), rest) {
+                (Instruction::LongStoreAddress { .. }, [n1, n2, ..]) =>
+                    Instruction::LongStoreAddress { addr: u16::from_be_bytes([*n1, *n2]) },
+                (Instruction::LongStoreAddress { .. }, _) => Instruction::UnknownInstruction,
+                (instruction, _) => instruction
+            },
+            _ => Instruction::UnknownInstruction
+        }
+    }
+
+    /// Size of the instruction in bytes, 4 for F000 NNNN and 2 for every other instruction
+    pub fn size(self) -> usize {
+        match self {
+            Instruction::LongStoreAddress { .. } => 4,
+            _ => 2
+        }
+    }
+
+    /// Opcode of the instruction, None for Unknown or when a field does not fit in its nibbles.
+    /// The address word following F000 is not part of the opcode
+    pub fn encode(self) -> Option<u16> {
+        match self {
+            Instruction::EndOfProgram => Some(0x0000),
+            Instruction::Clear => Some(0x00E0),
+            Instruction::Return => Some(0x00EE),
+            Instruction::ScrollDown { n } => Instruction::encode_xyn(0x0000, 0x0, 0xC, n),
+            Instruction::ScrollRight => Some(0x00FB),
+            Instruction::ScrollLeft => Some(0x00FC),
+            Instruction::Exit => Some(0x00FD),
+            Instruction::LowResolution => Some(0x00FE),
+            Instruction::HighResolution => Some(0x00FF),
+            // SYS addresses used by other 0NNN instructions can't be encoded
+            Instruction::CallProgram { addr } => match Instruction::encode_nnn(0x0000, addr).map(Instruction::from) {
+                Some(Instruction::CallProgram { .. }) => Some(addr),
+                _ => None
+            },
+            Instruction::Goto { addr } => Instruction::encode_nnn(0x1000, addr),
+            Instruction::CallSubroutine { addr } => Instruction::encode_nnn(0x2000, addr),
+            Instruction::SkipEqualU8 { x, value } => Instruction::encode_xnn(0x3000, x, value),
+            Instruction::SkipNotEqualU8 { x, value } => Instruction::encode_xnn(0x4000, x, value),
+            Instruction::SkipEqualReg { x, y } => Instruction::encode_xyn(0x5000, x, y, 0x0),
+            Instruction::SaveRange { x, y } => Instruction::encode_xyn(0x5000, x, y, 0x2),
+            Instruction::LoadRange { x, y } => Instruction::encode_xyn(0x5000, x, y, 0x3),
+            Instruction::SetFromU8 { x, value } => Instruction::encode_xnn(0x6000, x, value),
+            Instruction::AddU8 { x, value } => Instruction::encode_xnn(0x7000, x, value),
+            Instruction::SetFromReg { x, y } => Instruction::encode_xyn(0x8000, x, y, 0x0),
+            Instruction::OrReg { x, y } => Instruction::encode_xyn(0x8000, x, y, 0x1),
+            Instruction::AndReg { x, y } => Instruction::encode_xyn(0x8000, x, y, 0x2),
+            Instruction::XorReg { x, y } => Instruction::encode_xyn(0x8000, x, y, 0x3),
+            Instruction::AddReg { x, y } => Instruction::encode_xyn(0x8000, x, y, 0x4),
+            Instruction::SubReg { x, y } => Instruction::encode_xyn(0x8000, x, y, 0x5),
+            Instruction::ShiftRight { x, y } => Instruction::encode_xyn(0x8000, x, y, 0x6),
+            Instruction::RevSubReg { x, y } => Instruction::encode_xyn(0x8000, x, y, 0x7),
+            Instruction::ShiftLeft { x, y } => Instruction::encode_xyn(0x8000, x, y, 0xE),
+            Instruction::SkipNotEqualReg { x, y } => Instruction::encode_xyn(0x9000, x, y, 0x0),
+            Instruction::StoreAddress { addr } => Instruction::encode_nnn(0xA000, addr),
+            Instruction::LongStoreAddress { .. } => Some(0xF000),
+            Instruction::SelectPlanes { n } => Instruction::encode_xnn(0xF000, n, 0x01),
+            Instruction::LoadAudioPattern => Some(0xF002),
+            Instruction::SetPitch { x } => Instruction::encode_xnn(0xF000, x, 0x3A),
+            Instruction::JumpToAddress { addr } => Instruction::encode_nnn(0xB000, addr),
+            Instruction::Rand { x, value } => Instruction::encode_xnn(0xC000, x, value),
+            Instruction::Draw { x, y, n } => Instruction::encode_xyn(0xD000, x, y, n),
+            Instruction::SkipIfKeyPressed { x } => Instruction::encode_xnn(0xE000, x, 0x9E),
+            Instruction::SkipIfNotKeyPressed { x } => Instruction::encode_xnn(0xE000, x, 0xA1),
+            Instruction::SetFromDelayTimer { x } => Instruction::encode_xnn(0xF000, x, 0x07),
+            Instruction::AwaitKeyPressed { x } => Instruction::encode_xnn(0xF000, x, 0x0A),
+            Instruction::SetDelayTimer { x } => Instruction::encode_xnn(0xF000, x, 0x15),
+            Instruction::SetSoundTimer { x } => Instruction::encode_xnn(0xF000, x, 0x18),
+            Instruction::AddToI { x } => Instruction::encode_xnn(0xF000, x, 0x1E),
+            Instruction::SetIToSpriteAddress { x } => Instruction::encode_xnn(0xF000, x, 0x29),
+            Instruction::SetIToBigSpriteAddress { x } => Instruction::encode_xnn(0xF000, x, 0x30),
+            Instruction::StoreAtIAsDecimal { x } => Instruction::encode_xnn(0xF000, x, 0x33),
+            Instruction::DumpToMemory { x } => Instruction::encode_xnn(0xF000, x, 0x55),
+            Instruction::LoadFromMemory { x } => Instruction::encode_xnn(0xF000, x, 0x65),
+            Instruction::StoreFlags { x } => Instruction::encode_xnn(0xF000, x, 0x75),
+            Instruction::LoadFlags { x } => Instruction::encode_xnn(0xF000, x, 0x85),
+            Instruction::UnknownInstruction => None
+        }
+    }
+
+    fn encode_nnn(opcode: u16, addr: u16) -> Option<u16> {
+        if addr > 0xFFF {
+            return None;
+        }
+        Some(opcode | addr)
+    }
+
+    fn encode_xnn(opcode: u16, x: u8, value: u8) -> Option<u16> {
+        if x > 0xF {
+            return None;
+        }
+        Some(opcode | (x as u16) << 8 | value as u16)
+    }
+
+    fn encode_xyn(opcode: u16, x: u8, y: u8, n: u8) -> Option<u16> {
+        if y > 0xF || n > 0xF {
+            return None;
+        }
+        Instruction::encode_xnn(opcode, x, y << 4 | n)
+    }
+
     fn value_from(n1: u8, n2: u8) -> u8 {
         ((n1 << 4) & 0xF0) | (n2 & 0xF)
     }
@@ -275,4 +441,60 @@ mod tests {
         let instruction = Instruction::from((0x0, 0xE0));
         assert_eq!(instruction, Instruction::Clear);
     }
+
+    #[test]
+    fn test_shift_keeps_y() {
+        match Instruction::from(0x812E) {
+            Instruction::ShiftLeft { x: 1, y: 2 } => (),
+            instruction => panic!("Bad decoding {}", instruction.to_asm())
+        }
+        assert_eq!(Instruction::from(0x8AB6).to_asm(), "SHR V10, V11");
+    }
+
+    #[test]
+    fn test_schip_instructions() {
+        assert_eq!(Instruction::from(0x00C4), Instruction::ScrollDown { n: 4 });
+        assert_eq!(Instruction::from(0x00FF), Instruction::HighResolution);
+        assert_eq!(Instruction::from(0xF330).to_asm(), "LD HF, V3");
+        assert_eq!(Instruction::from(0xF775).to_asm(), "LD R, V7");
+        assert_eq!(Instruction::from(0xF585).to_asm(), "LD V5, R");
+        assert_eq!(Instruction::from(0x00C1).to_asm(), "SCD 1");
+    }
+
+    #[test]
+    fn test_xochip_instructions() {
+        assert_eq!(Instruction::decode(&[0xF0, 0x00, 0x12, 0x34]), Instruction::LongStoreAddress { addr: 0x1234 });
+        assert_eq!(Instruction::decode(&[0xF0, 0x00, 0x12]), Instruction::UnknownInstruction);
+        assert_eq!(Instruction::decode(&[0xF0, 0x00, 0x12, 0x34]).to_asm(), "LD I, long 0x1234");
+        assert_eq!(Instruction::decode(&[0x61, 0x02]).size(), 2);
+        assert_eq!(Instruction::from(0xF201), Instruction::SelectPlanes { n: 2 });
+        assert_eq!(Instruction::from(0x5AB2).to_asm(), "SAVE V10, V11");
+        assert_eq!(Instruction::from(0x5123).to_asm(), "LOAD V1, V2");
+        assert_eq!(Instruction::from(0xF002), Instruction::LoadAudioPattern);
+        assert_eq!(Instruction::from(0xF43A).to_asm(), "PITCH V4");
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        for opcode in 0..=0xFFFF {
+            let instruction = Instruction::from(opcode);
+            match instruction.encode() {
+                Some(encoded) => {
+                    assert_eq!(encoded, opcode, "{:?}", instruction);
+                    assert_eq!(Instruction::from(encoded), instruction);
+                },
+                None => assert_eq!(instruction, Instruction::UnknownInstruction, "0x{:04X}", opcode)
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_out_of_range() {
+        assert_eq!(Instruction::Goto { addr: 0x1000 }.encode(), None);
+        assert_eq!(Instruction::Draw { x: 1, y: 2, n: 16 }.encode(), None);
+        assert_eq!(Instruction::AddToI { x: 16 }.encode(), None);
+        assert_eq!(Instruction::CallProgram { addr: 0x0E0 }.encode(), None);
+        assert_eq!(Instruction::CallProgram { addr: 0x0FF }.encode(), None);
+        assert_eq!(Instruction::CallProgram { addr: 0x123 }.encode(), Some(0x0123));
+    }
 }
@@ -1,3 +1,12 @@
 pub mod vm;
 pub mod config;
-mod instructions;
+pub mod dump;
+pub mod frontend;
+pub mod machine;
+pub mod rewind;
+pub mod clock;
+pub mod timing;
+pub mod debugger;
+pub mod assembler;
+#[allow(clippy::empty_line_after_doc_comments)]
+pub mod instructions;
@@ -0,0 +1,291 @@
+use std::fs::File;
+use std::path::PathBuf;
+
+use log::{ warn };
+
+use crate::clock::Clock;
+use crate::frontend::{ Frontend, Command };
+use crate::rewind::RewindBuffer;
+use crate::timing::VipTiming;
+use crate::vm::{ VM, VmError, TIMER_FREQUENCY };
+
+/// VM plugged to a frontend, running at 60 frames per second
+pub struct Machine<F: Frontend> {
+    vm: VM,
+    frontend: F,
+
+    /// Instructions executed per frame and pacing of the frames
+    clock: Clock,
+
+    /// COSMAC VIP instruction durations, None to run cycles_per_frame instructions per frame
+    timing: Option<VipTiming>,
+
+    /// Whether the frontend is currently beeping
+    beeping: bool,
+
+    /// Path of the save states, the slot number is appended to it
+    state_path: PathBuf,
+
+    /// Recent history, None when rewind is disabled
+    rewind: Option<RewindBuffer>
+}
+
+impl<F: Frontend> Machine<F> {
+    pub fn new(vm: VM, frontend: F, cycles_per_frame: usize) -> Self {
+        let ips = cycles_per_frame.max(1) as u32 * TIMER_FREQUENCY;
+        Machine::with_clock(vm, frontend, Clock::new(ips))
+    }
+
+    pub fn with_clock(vm: VM, frontend: F, clock: Clock) -> Self {
+        Machine {
+            vm,
+            frontend,
+            clock,
+            timing: None,
+            beeping: false,
+            state_path: PathBuf::from("chip8"),
+            rewind: None
+        }
+    }
+
+    /// Keep depth snapshots of the VM taken every interval frames
+    pub fn enable_rewind(&mut self, depth: usize, interval: u32) {
+        self.rewind = Some(RewindBuffer::new(depth, interval));
+    }
+
+    /// Go back about frames frames in time, returns false if there is no history
+    pub fn rewind(&mut self, frames: u32) -> bool {
+        match self.rewind.as_mut() {
+            Some(rewind) => rewind.rewind(&mut self.vm, frames),
+            None => false
+        }
+    }
+
+    /// Run each frame for the machine cycles the COSMAC VIP had instead of a fixed
+    /// number of instructions
+    pub fn enable_vip_timing(&mut self) {
+        self.timing = Some(VipTiming::new());
+    }
+
+    /// Save states are stored in files named <path>.state<slot>
+    pub fn set_state_path(&mut self, path: PathBuf) {
+        self.state_path = path;
+    }
+
+    /// File of a save state slot
+    pub fn slot_path(&self, slot: u8) -> PathBuf {
+        let mut path = self.state_path.clone().into_os_string();
+        path.push(format!(".state{}", slot));
+        path.into()
+    }
+
+    pub fn save_slot(&mut self, slot: u8) -> Result<(), VmError> {
+        let mut file = File::create(self.slot_path(slot))?;
+        self.vm.save_state(&mut file)
+    }
+
+    pub fn load_slot(&mut self, slot: u8) -> Result<(), VmError> {
+        let mut file = File::open(self.slot_path(slot))?;
+        self.vm.load_state(&mut file)
+    }
+
+    /// Wait for the end of each frame, disabled to run as fast as possible (turbo)
+    pub fn set_paced(&mut self, paced: bool) {
+        self.clock.set_turbo(!paced);
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub fn clock_mut(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    pub fn frontend(&self) -> &F {
+        &self.frontend
+    }
+
+    pub fn frontend_mut(&mut self) -> &mut F {
+        &mut self.frontend
+    }
+
+    pub fn into_parts(self) -> (VM, F) {
+        (self.vm, self.frontend)
+    }
+
+    /// Whether the program is still running and the user did not quit
+    pub fn is_running(&self) -> bool {
+        self.vm.run() && !self.frontend.should_quit()
+    }
+
+    /// Run a single frame of at most max_cycles instructions: input, execution, display and sound.
+    /// Nothing is executed while the clock is paused. Returns the number of instructions executed
+    pub fn step_frame(&mut self, max_cycles: usize) -> Result<usize, VmError> {
+        for event in self.frontend.poll_input()? {
+            self.vm.push_key_event(event);
+        }
+        for command in self.frontend.poll_commands() {
+            self.execute_command(command);
+        }
+        let cycles = match self.clock.start_frame() {
+            Some(frame_cycles) => self.run_frame(max_cycles, frame_cycles)?,
+            None => 0
+        };
+        self.frontend.present(self.vm.display())?;
+        self.vm.clear_display_dirty();
+
+        if self.vm.is_sound_active() != self.beeping {
+            self.beeping = !self.beeping;
+            if self.beeping {
+                self.frontend.start_beep();
+            } else {
+                self.frontend.stop_beep();
+            }
+        }
+        Ok(cycles)
+    }
+
+    /// Execute the frame_cycles instructions of the clock, or those fitting in the frame
+    /// with the COSMAC VIP timing, then tick the timers
+    fn run_frame(&mut self, max_cycles: usize, frame_cycles: usize) -> Result<usize, VmError> {
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.record(&self.vm);
+        }
+        match self.timing.as_mut() {
+            Some(timing) => timing.run_frame(&mut self.vm, max_cycles),
+            None => self.vm.run_frame(max_cycles.min(frame_cycles))
+        }
+    }
+
+    /// Commands failures are reported to the user, they do not stop the machine
+    fn execute_command(&mut self, command: Command) {
+        let (result, action, slot) = match command {
+            Command::SaveState(slot) => (self.save_slot(slot), "Saved", slot),
+            Command::LoadState(slot) => (self.load_slot(slot), "Loaded", slot),
+            Command::Rewind(frames) => {
+                if !self.rewind(frames) {
+                    self.frontend.notify("Nothing to rewind");
+                }
+                return;
+            },
+            Command::TogglePause => {
+                self.clock.set_paused(!self.clock.is_paused());
+                self.frontend.notify(if self.clock.is_paused() { "Paused" } else { "Resumed" });
+                return;
+            },
+            Command::AdvanceFrame => {
+                self.clock.advance_frame();
+                return;
+            },
+            Command::ToggleTurbo => {
+                self.clock.set_turbo(!self.clock.is_turbo());
+                self.frontend.notify(if self.clock.is_turbo() { "Turbo" } else { "Normal speed" });
+                return;
+            },
+            Command::SpeedUp | Command::SlowDown => {
+                if command == Command::SpeedUp {
+                    self.clock.speed_up();
+                } else {
+                    self.clock.slow_down();
+                }
+                let message = format!("Speed x{}", self.clock.speed());
+                self.frontend.notify(&message);
+                return;
+            },
+            Command::ShowSpeed => {
+                let message = format!(
+                    "Speed x{}{}, {:.0} instructions per second",
+                    self.clock.speed(),
+                    if self.clock.is_turbo() { " (turbo)" } else { "" },
+                    self.clock.achieved_ips()
+                );
+                self.frontend.notify(&message);
+                return;
+            }
+        };
+
+        match result {
+            Ok(()) => self.frontend.notify(&format!("{} slot {}", action, slot)),
+            Err(err) => {
+                warn!("Slot {}: {}", slot, err);
+                self.frontend.notify(&format!("Slot {}: {}", slot, err));
+            }
+        }
+    }
+
+    /// Run until the program ends or the user quits
+    pub fn run(&mut self) -> Result<(), VmError> {
+        self.run_for(u64::MAX).map(|_| ())
+    }
+
+    /// Run until the program ends, the user quits or max_cycles instructions
+    /// are executed. Returns the number of instructions executed
+    pub fn run_for(&mut self, max_cycles: u64) -> Result<u64, VmError> {
+        let mut cycles = 0;
+
+        while cycles < max_cycles && self.is_running() {
+            let max_frame_cycles = (max_cycles - cycles).min(usize::MAX as u64);
+            let frame_cycles = self.step_frame(max_frame_cycles as usize)?;
+            cycles += frame_cycles as u64;
+            self.clock.end_frame(frame_cycles);
+        }
+        Ok(cycles)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::frontend::headless::Headless;
+
+    #[test]
+    fn test_run_for() {
+        // LD V0, 1; ADD V1, 1; JP 0x202
+        let vm = VM::try_from(&[0x60, 0x01, 0x71, 0x01, 0x12, 0x02][..]).unwrap();
+        let mut machine = Machine::new(vm, Headless::new(), 10);
+        machine.set_paced(false);
+
+        assert_eq!(machine.run_for(25).unwrap(), 25);
+        assert_eq!(machine.frontend().frames(), 3);
+        assert_eq!(machine.vm().registers()[1], 12);
+    }
+
+    #[test]
+    fn test_vip_timing() {
+        // LD V0, 1; DRW V0, V0, 1; JP 0x202
+        let vm = VM::try_from(&[0x60, 0x01, 0xD0, 0x01, 0x12, 0x02][..]).unwrap();
+        let mut machine = Machine::new(vm, Headless::new(), 100);
+        machine.set_paced(false);
+        machine.enable_vip_timing();
+
+        assert_eq!(machine.run_for(10).unwrap(), 10);
+        assert_eq!(machine.frontend().frames(), 5);
+    }
+
+    #[test]
+    fn test_paused_clock() {
+        // ADD V1, 1; JP 0x200
+        let vm = VM::try_from(&[0x71, 0x01, 0x12, 0x00][..]).unwrap();
+        let mut machine = Machine::with_clock(vm, Headless::new(), Clock::new(600));
+        machine.clock_mut().set_paused(true);
+
+        assert_eq!(machine.step_frame(usize::MAX).unwrap(), 0);
+        assert_eq!(machine.frontend().frames(), 1);
+        machine.clock_mut().advance_frame();
+        assert_eq!(machine.step_frame(usize::MAX).unwrap(), 10);
+        assert_eq!(machine.step_frame(usize::MAX).unwrap(), 0);
+        assert_eq!(machine.vm().registers()[1], 5);
+    }
+}
@@ -1,27 +1,137 @@
 use std::path::{ Path, PathBuf };
-use std::convert::TryFrom;
+use std::error::Error;
+use std::fs::{ self, File };
 use std::io;
+use std::process;
+use std::time::Instant;
 
 use log::{ info };
 
-use chip8::config::Config;
-use chip8::vm::VM;
+use chip8::assembler;
+use chip8::clock::Clock;
+use chip8::config::{ Config, FrontendKind };
+use chip8::debugger::Debugger;
+use chip8::dump;
+use chip8::frontend::Frontend;
+use chip8::frontend::headless::Headless;
+use chip8::frontend::terminal::Terminal;
+use chip8::machine::Machine;
+use chip8::timing::TimingModel;
+use chip8::vm::{ VM, VmBuilder, Keymap, TIMER_FREQUENCY };
 
-fn main() -> io::Result<()> {
-    env_logger::init();
+/// Run the machine, save states are stored next to the ROM and the RPL
+/// user flags written back on exit
+fn run_machine<F: Frontend>(machine: &mut Machine<F>, config: &Config, cycles: u64) -> Result<(), Box<dyn Error>> {
+    machine.set_state_path(PathBuf::from(&config.file));
+    if config.timing == TimingModel::Vip {
+        machine.enable_vip_timing();
+    }
+    if config.rewind_depth > 0 {
+        machine.enable_rewind(config.rewind_depth, config.rewind_interval);
+    }
+    if let Some(slot) = config.load_state {
+        machine.load_slot(slot)?;
+    }
+
+    let start = Instant::now();
+    let cycles = machine.run_for(cycles);
+    machine.vm().save_rpl_flags()?;
+    let cycles = cycles?;
+    info!(
+        "Machine stopped after {} cycles ({:.0} instructions per second)",
+        cycles,
+        cycles as f64 / start.elapsed().as_secs_f64()
+    );
+
+    if let Some(slot) = config.save_state {
+        machine.save_slot(slot)?;
+    }
+    Ok(())
+}
+
+/// Run config.cycles instructions without any frontend then dump the VM state
+fn run_headless(vm: VM, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut machine = Machine::with_clock(vm, Headless::new(), clock(config));
+    machine.set_paced(false);
+    run_machine(&mut machine, config, config.cycles)?;
+
+    match &config.output {
+        Some(path) => dump::write_state(machine.vm(), config.dump, &mut File::create(path)?)?,
+        None => dump::write_state(machine.vm(), config.dump, &mut io::stdout())?
+    }
+    Ok(())
+}
+
+/// Assemble config.file into a ROM written next to it or to --output
+fn assemble(config: &Config) -> Result<(), Box<dyn Error>> {
+    let source = fs::read_to_string(&config.file)?;
+    let rom = assembler::assemble(&source).map_err(|err| format!("{}:{}", config.file, err))?;
+
+    let output = match &config.output {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&config.file).with_extension("rom")
+    };
+    fs::write(&output, &rom)?;
+    info!("Assembled {} bytes into {}", rom.len(), output.display());
+    Ok(())
+}
+
+/// Clock running config.clock instructions per second at config.speed
+fn clock(config: &Config) -> Clock {
+    let mut clock = Clock::new(config.clock);
+    clock.set_speed(config.speed);
+    clock.set_turbo(config.turbo);
+    clock
+}
+
+fn cycles_per_frame(config: &Config) -> usize {
+    (config.clock / TIMER_FREQUENCY).max(1) as usize
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
     let config = Config::from_args()?;
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = config.log_level {
+        logger.filter_level(level);
+    }
+    logger.init();
+
+    if config.assemble {
+        return assemble(&config);
+    }
+
     let rom_path: PathBuf = Path::new(&(config.file)).into();
-    let mut vm = VM::try_from(rom_path)?;
+    let mut builder = VmBuilder::new().quirks(config.quirks);
+    if let Some(seed) = config.seed {
+        builder = builder.seed(seed);
+    }
+    if let Some(dir) = &config.flags_dir {
+        builder = builder.flags_dir(dir.clone());
+    }
+    let vm = builder.build_from_file(rom_path)?;
 
     if config.disassemble {
-        for (i, x) in vm.get_program().iter().enumerate() {
-            println!("{} {}", i, x);
+        for (addr, x) in vm.get_program() {
+            println!("{:<20} ; 0x{:03X}", x, addr);
         }
+    } else if config.debug {
+        let mut debugger = Debugger::new(vm, cycles_per_frame(&config));
+        debugger.repl(io::stdin().lock(), &mut io::stdout())?;
+        debugger.vm().save_rpl_flags()?;
+    } else if config.frontend == FrontendKind::Headless {
+        run_headless(vm, &config)?;
     } else {
-        while vm.run() {
-            let instruction = vm.execute_next()?;
-            info!("({} -> {}) Instruction executed", instruction.to_asm(), instruction);
-        }
+        let terminal = Terminal::new(Keymap::default(), config.scale as usize)?;
+        let mut machine = Machine::with_clock(vm, terminal, clock(&config));
+        machine.clock_mut().set_paused(config.paused);
+        run_machine(&mut machine, &config, u64::MAX)?;
     }
     Ok(())
 }
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+
+use crate::vm::{ VM, Snapshot };
+
+/// Memory or display pixels of a snapshot, only the newest one is stored in full
+enum Memory {
+    Full(Vec<u8>),
+
+    /// Runs of bytes (address, bytes) differing from the next newer snapshot
+    Delta(Vec<(usize, Vec<u8>)>)
+}
+
+impl Memory {
+
+    /// Keep only the bytes differing from newer. Kept in full when the sizes differ,
+    /// after a change of resolution
+    fn compress(&mut self, newer: &[u8]) {
+        if let Memory::Full(bytes) = self {
+            if bytes.len() == newer.len() {
+                *self = Memory::Delta(delta(bytes, newer));
+            }
+        }
+    }
+
+    /// Rebuild the full bytes from those of the next newer snapshot
+    fn expand(&mut self, newer: &[u8]) {
+        if let Memory::Delta(runs) = self {
+            let mut bytes = newer.to_vec();
+            for (addr, run) in runs.iter() {
+                bytes[*addr..*addr + run.len()].copy_from_slice(run);
+            }
+            *self = Memory::Full(bytes);
+        }
+    }
+
+    fn into_full(self) -> Vec<u8> {
+        match self {
+            Memory::Full(bytes) => bytes,
+            Memory::Delta(_) => unreachable!("newest snapshot is always full")
+        }
+    }
+
+    /// Approximate size in bytes
+    fn size(&self) -> usize {
+        match self {
+            Memory::Full(bytes) => bytes.len(),
+            Memory::Delta(runs) => runs.iter().map(|(_, bytes)| bytes.len() + 8).sum()
+        }
+    }
+}
+
+struct Entry {
+
+    /// Snapshot with its memory and display pixels moved out
+    snapshot: Snapshot,
+    memory: Memory,
+    display: Memory
+}
+
+/// Ring buffer of periodic VM snapshots to step back through recent execution.
+/// Memory and display are delta compressed between consecutive snapshots
+pub struct RewindBuffer {
+
+    /// Maximum number of snapshots kept
+    depth: usize,
+
+    /// Frames between two snapshots
+    interval: u32,
+
+    /// Frames recorded since the last snapshot
+    frames: u32,
+
+    /// Snapshots, oldest first
+    entries: VecDeque<Entry>
+}
+
+impl RewindBuffer {
+    pub fn new(depth: usize, interval: u32) -> Self {
+        RewindBuffer {
+            depth: depth.max(1),
+            interval: interval.max(1),
+            frames: 0,
+            entries: VecDeque::new()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.frames = 0;
+    }
+
+    /// Approximate size in bytes of the stored memory and displays
+    pub fn memory_usage(&self) -> usize {
+        self.entries.iter().map(|entry| entry.memory.size() + entry.display.size()).sum()
+    }
+
+    /// To be called once per frame, captures the VM every interval frames
+    pub fn record(&mut self, vm: &VM) {
+        if self.frames == 0 {
+            self.push(vm.snapshot());
+        }
+        self.frames = (self.frames + 1) % self.interval;
+    }
+
+    /// Restore the VM as it was about frames ago, returns false if there is no history
+    /// or if it was recorded from a VM of another memory size. Rewinding further than
+    /// the history restores the oldest snapshot
+    pub fn rewind(&mut self, vm: &mut VM, frames: u32) -> bool {
+        // u32::div_ceil needs Rust 1.73
+        #[allow(clippy::manual_div_ceil)]
+        let steps = ((frames + self.interval - 1) / self.interval).max(1);
+        let mut snapshot = None;
+
+        for _ in 0..steps {
+            match self.pop() {
+                Some(popped) => snapshot = Some(popped),
+                None => break
+            }
+        }
+        match snapshot {
+            Some(snapshot) => {
+                self.frames = 0;
+                vm.restore(&snapshot).is_ok()
+            },
+            None => false
+        }
+    }
+
+    fn push(&mut self, mut snapshot: Snapshot) {
+        let memory = std::mem::take(&mut snapshot.memory);
+        let display = snapshot.display.take_pixels();
+
+        if let Some(newest) = self.entries.back_mut() {
+            newest.memory.compress(&memory);
+            newest.display.compress(&display);
+        }
+        self.entries.push_back(Entry { snapshot, memory: Memory::Full(memory), display: Memory::Full(display) });
+        while self.entries.len() > self.depth {
+            self.entries.pop_front();
+        }
+    }
+
+    fn pop(&mut self) -> Option<Snapshot> {
+        let entry = self.entries.pop_back()?;
+        let memory = entry.memory.into_full();
+        let display = entry.display.into_full();
+
+        if let Some(newest) = self.entries.back_mut() {
+            newest.memory.expand(&memory);
+            newest.display.expand(&display);
+        }
+        let mut snapshot = entry.snapshot;
+        snapshot.memory = memory;
+        snapshot.display.set_pixels(display);
+        Some(snapshot)
+    }
+}
+
+/// Runs of bytes of old differing from new
+fn delta(old: &[u8], new: &[u8]) -> Vec<(usize, Vec<u8>)> {
+    let mut runs: Vec<(usize, Vec<u8>)> = vec![];
+
+    for (addr, (o, n)) in old.iter().zip(new.iter()).enumerate() {
+        if o == n {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((start, bytes)) if *start + bytes.len() == addr => bytes.push(*o),
+            _ => runs.push((addr, vec![*o]))
+        }
+    }
+    runs
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    /// ADD V0, 1; LD I, 0x300; LD [I], V0; JP 0x200, one instruction per frame
+    const ROM: [u8; 8] = [0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x00];
+
+    fn run_frames(vm: &mut VM, rewind: &mut RewindBuffer, frames: usize) {
+        for _ in 0..frames {
+            rewind.record(vm);
+            vm.run_frame(1).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut vm = VM::try_from(&ROM[..]).unwrap();
+        let mut rewind = RewindBuffer::new(100, 4);
+        run_frames(&mut vm, &mut rewind, 40);
+        assert_eq!(rewind.len(), 10);
+
+        // Snapshot taken at frame 32, before the 9th ADD
+        assert!(rewind.rewind(&mut vm, 8));
+        assert_eq!(vm.pc(), 0x200);
+        assert_eq!(vm.registers()[0], 8);
+        assert_eq!(vm.memory()[0x300], 8);
+
+        assert!(rewind.rewind(&mut vm, 4));
+        assert_eq!(vm.registers()[0], 7);
+        assert_eq!(vm.memory()[0x300], 7);
+        assert_eq!(rewind.len(), 7);
+
+        assert!(rewind.rewind(&mut vm, 1000));
+        assert_eq!(vm.registers()[0], 0);
+        assert_eq!(vm.memory()[0x300], 0);
+        assert!(!rewind.rewind(&mut vm, 1));
+    }
+
+    #[test]
+    fn test_rewind_depth() {
+        let mut vm = VM::try_from(&ROM[..]).unwrap();
+        let mut rewind = RewindBuffer::new(5, 1);
+        run_frames(&mut vm, &mut rewind, 100);
+
+        assert_eq!(rewind.len(), 5);
+        // Newest memory and display in full, the older ones only differ by V0 stored at 0x300
+        assert!(rewind.memory_usage() < 4096 + 64 * 32 + 5 * 16);
+        assert!(rewind.rewind(&mut vm, 1000));
+        assert_eq!(vm.registers()[0], 24);
+    }
+
+    #[test]
+    fn test_rewind_display() {
+        // LD I, 0x200; DRW V0, V1, 4; ADD V0, 8; JP 0x202
+        let mut vm = VM::try_from(&[0xA2, 0x00, 0xD0, 0x14, 0x70, 0x08, 0x12, 0x02][..]).unwrap();
+        let mut rewind = RewindBuffer::new(10, 1);
+        run_frames(&mut vm, &mut rewind, 4);
+        let expected = vm.snapshot();
+
+        run_frames(&mut vm, &mut rewind, 6);
+        assert_ne!(vm.display(), &expected.display);
+        assert!(rewind.rewind(&mut vm, 6));
+        assert_eq!(vm.snapshot(), expected);
+    }
+
+    #[test]
+    fn test_delta() {
+        let runs = delta(&[0, 1, 2, 3, 4, 5], &[0, 9, 9, 3, 4, 9]);
+        assert_eq!(runs, vec![(1, vec![1, 2]), (5, vec![5])]);
+    }
+}
@@ -0,0 +1,201 @@
+use std::str::FromStr;
+
+use crate::instructions::Instruction;
+use crate::vm::{ VM, VmError, TIMER_FREQUENCY };
+
+/// Clock of the COSMAC VIP RCA 1802 CPU in Hz
+pub const VIP_CLOCK: u32 = 1_760_640;
+
+/// Machine cycles per second, an 1802 machine cycle lasts 8 clock cycles
+pub const MACHINE_CYCLE_FREQUENCY: u32 = VIP_CLOCK / 8;
+
+/// Machine cycles between two display interrupts
+pub const FRAME_CYCLES: u32 = MACHINE_CYCLE_FREQUENCY / TIMER_FREQUENCY;
+
+/// Machine cycles of each frame taken by the CDP1861 display DMA (128 lines of 8 bytes)
+/// and by the interrupt routine decrementing the timers
+pub const DISPLAY_CYCLES: u32 = 1024 + 46;
+
+/// Fetch and decode of an instruction by the interpreter loop
+pub const FETCH_CYCLES: u32 = 40;
+
+/// Instructions pacing of the runner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimingModel {
+    /// Fixed number of instructions per frame, set by the clock
+    Fixed,
+
+    /// Machine cycles of each instruction on the COSMAC VIP
+    Vip
+}
+
+impl FromStr for TimingModel {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "fixed" => Ok(TimingModel::Fixed),
+            "vip" => Ok(TimingModel::Vip),
+            _ => Err(())
+        }
+    }
+}
+
+/// Machine cycles taken by the VIP interpreter to execute an instruction, fetch included.
+/// regs are the registers before the execution and skipped whether the instruction
+/// skipped the next one. The wait for the display interrupt preceding DXYN is not
+/// included, see VipTiming
+pub fn vip_cycles(instruction: &Instruction, regs: &[u8; 16], skipped: bool) -> u32 {
+    let skip = if skipped { 4 } else { 0 };
+    let reg = |x: u8| regs[x as usize] as u32;
+
+    FETCH_CYCLES + match *instruction {
+        Instruction::Clear => 24,
+        Instruction::Return => 10,
+        Instruction::Goto { .. } => 12,
+        Instruction::CallSubroutine { .. } => 26,
+        Instruction::SkipEqualU8 { .. } | Instruction::SkipNotEqualU8 { .. } => 10 + skip,
+        Instruction::SkipEqualReg { .. } | Instruction::SkipNotEqualReg { .. } => 14 + skip,
+        Instruction::SetFromU8 { .. } => 6,
+        Instruction::AddU8 { .. } => 10,
+        Instruction::SetFromReg { .. } | Instruction::OrReg { .. } | Instruction::AndReg { .. }
+            | Instruction::XorReg { .. } | Instruction::AddReg { .. } | Instruction::SubReg { .. }
+            | Instruction::ShiftRight { .. } | Instruction::RevSubReg { .. }
+            | Instruction::ShiftLeft { .. } => 44,
+        Instruction::StoreAddress { .. } => 12,
+
+        // Crossing a page costs an extra long branch
+        Instruction::JumpToAddress { addr } => {
+            if (addr as u32 + reg(0)) >> 8 != addr as u32 >> 8 { 24 } else { 22 }
+        },
+        Instruction::Rand { .. } => 36,
+
+        // Unaligned sprites are shifted across two bytes of the display
+        Instruction::Draw { x, n, .. } => {
+            let row = if reg(x) % 8 == 0 { 22 } else { 34 };
+            26 + n as u32 * row
+        },
+        Instruction::SkipIfKeyPressed { .. } | Instruction::SkipIfNotKeyPressed { .. } => 14 + skip,
+        Instruction::SetFromDelayTimer { .. } | Instruction::SetDelayTimer { .. }
+            | Instruction::SetSoundTimer { .. } => 10,
+        Instruction::AwaitKeyPressed { .. } => 10,
+        Instruction::AddToI { .. } => 16,
+        Instruction::SetIToSpriteAddress { .. } => 20,
+
+        // Digits are computed by repeated subtractions of 100 and 10
+        Instruction::StoreAtIAsDecimal { x } => {
+            let value = reg(x);
+            80 + 16 * (value / 100 + value / 10 % 10 + value % 10)
+        },
+        Instruction::DumpToMemory { x } | Instruction::LoadFromMemory { x } => 14 + 14 * (x as u32 + 1),
+
+        // Machine code routines run for an unknown time
+        Instruction::CallProgram { .. } => 0,
+        Instruction::EndOfProgram | Instruction::UnknownInstruction => 0,
+
+        // SCHIP and XO-CHIP extensions did not exist on the VIP
+        _ => 10
+    }
+}
+
+/// Paces the VM at the speed of the COSMAC VIP: each frame runs the instructions
+/// fitting in the machine cycles left by the display, DXYN waits for the next
+/// display interrupt
+#[derive(Debug, Default)]
+pub struct VipTiming {
+
+    /// Machine cycles run past the end of the previous frame
+    overrun: u32
+}
+
+impl VipTiming {
+    pub fn new() -> Self {
+        VipTiming::default()
+    }
+
+    /// Execute one 60Hz frame of at most max_instructions instructions then a timers tick.
+    /// Returns the number of instructions run
+    pub fn run_frame(&mut self, vm: &mut VM, max_instructions: usize) -> Result<usize, VmError> {
+        let budget = FRAME_CYCLES - DISPLAY_CYCLES;
+        let mut cycles = self.overrun.min(budget);
+        self.overrun -= cycles;
+        let mut instructions = 0;
+
+        while cycles < budget && instructions < max_instructions && vm.run() {
+            let (pc, regs) = (vm.pc(), *vm.registers());
+            let instruction = vm.execute_next()?;
+            instructions += 1;
+
+            let skipped = vm.pc() > pc + instruction.size();
+            let cost = vip_cycles(&instruction, &regs, skipped);
+            match instruction {
+
+                // The sprite is drawn after the interrupt, at the start of the next frame
+                Instruction::Draw { .. } => {
+                    self.overrun += cost;
+                    break;
+                },
+
+                // The interpreter scans the keypad until the end of the frame
+                Instruction::AwaitKeyPressed { .. } if vm.is_awaiting_key() => break,
+                _ => cycles += cost
+            }
+        }
+        self.overrun += cycles.saturating_sub(budget);
+        vm.tick_timers();
+        Ok(instructions)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    #[test]
+    fn test_vip_cycles() {
+        let mut regs = [0; 16];
+        assert_eq!(vip_cycles(&Instruction::SetFromU8 { x: 0, value: 1 }, &regs, false), 46);
+        assert_eq!(vip_cycles(&Instruction::SkipEqualU8 { x: 0, value: 0 }, &regs, true), 54);
+
+        let draw = Instruction::Draw { x: 1, y: 2, n: 5 };
+        assert_eq!(vip_cycles(&draw, &regs, false), 40 + 26 + 5 * 22);
+        regs[1] = 3;
+        assert_eq!(vip_cycles(&draw, &regs, false), 40 + 26 + 5 * 34);
+
+        regs[2] = 255;
+        assert_eq!(vip_cycles(&Instruction::StoreAtIAsDecimal { x: 2 }, &regs, false), 40 + 80 + 16 * 12);
+        assert_eq!(vip_cycles(&Instruction::JumpToAddress { addr: 0x2FF }, &regs, false), 62);
+        regs[0] = 1;
+        assert_eq!(vip_cycles(&Instruction::JumpToAddress { addr: 0x2FF }, &regs, false), 64);
+    }
+
+    #[test]
+    fn test_vip_frame() {
+        // LD V0, 1; ADD V1, 1; JP 0x202
+        let mut vm = VM::try_from(&[0x60, 0x01, 0x71, 0x01, 0x12, 0x02][..]).unwrap();
+        let mut timing = VipTiming::new();
+
+        let budget = FRAME_CYCLES - DISPLAY_CYCLES;
+        let instructions = timing.run_frame(&mut vm, usize::MAX).unwrap();
+        let spent = 46 + (instructions as u32 - 1) / 2 * (50 + 52) + (instructions as u32 - 1) % 2 * 50;
+        assert!(spent >= budget && spent - budget < 52);
+        assert_eq!(timing.overrun, spent - budget);
+
+        assert_eq!(timing.run_frame(&mut vm, 3).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_vip_draw_waits_interrupt() {
+        // LD V0, 1; DRW V0, V0, 5; JP 0x202
+        let mut vm = VM::try_from(&[0x60, 0x01, 0xD0, 0x05, 0x12, 0x02][..]).unwrap();
+        let mut timing = VipTiming::new();
+
+        assert_eq!(timing.run_frame(&mut vm, usize::MAX).unwrap(), 2);
+        assert_eq!(timing.overrun, 40 + 26 + 5 * 34);
+        assert_eq!(timing.run_frame(&mut vm, usize::MAX).unwrap(), 2);
+        assert_eq!(timing.overrun, 40 + 26 + 5 * 34);
+    }
+}
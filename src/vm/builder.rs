@@ -0,0 +1,164 @@
+use std::path::PathBuf;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ self, Read };
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+use crate::vm::{ VM, VmError, Quirks, DrawMode, KeyWaitMode, ShiftMode, Framebuffer, flags_path, START_ADDR, XO_MEMORY_SIZE, DEFAULT_PITCH, PLANE_1 };
+use crate::vm::flags::read_flags;
+use crate::vm::font::{ DEFAULT_FONT, FONT_ADDR, FONT_SIZE, DEFAULT_BIG_FONT, BIG_FONT_ADDR, BIG_FONT_SIZE };
+
+/// Configure and create a VM from a ROM
+pub struct VmBuilder {
+
+    /// 0 to F font loaded in the interpreter area
+    font: Vec<u8>,
+
+    /// Interpreter specific behaviours
+    quirks: Quirks,
+
+    /// Seed of the random number generator, None for a random seed
+    seed: Option<u64>,
+
+    /// Memory size in bytes, None for the size of the quirks
+    memory_size: Option<usize>,
+
+    /// Directory persisting the RPL user flags, None to keep them in memory only
+    flags_dir: Option<PathBuf>
+}
+
+impl Default for VmBuilder {
+    fn default() -> Self {
+        VmBuilder {
+            font: DEFAULT_FONT.to_vec(),
+            quirks: Quirks::default(),
+            seed: None,
+            memory_size: None,
+            flags_dir: None
+        }
+    }
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        VmBuilder::default()
+    }
+
+    /// Use an alternative font, 16 glyphs of 5 bytes each from 0 to F
+    pub fn font(mut self, font: &[u8]) -> Self {
+        self.font = font.to_vec();
+        self
+    }
+
+    /// Use an alternative font read from a raw binary file
+    pub fn font_file(self, file_path: PathBuf) -> io::Result<Self> {
+        let mut font = vec![];
+
+        File::open(file_path)?.read_to_end(&mut font)?;
+        Ok(self.font(&font))
+    }
+
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn draw_mode(mut self, mode: DrawMode) -> Self {
+        self.quirks.draw_mode = mode;
+        self
+    }
+
+    pub fn key_wait_mode(mut self, mode: KeyWaitMode) -> Self {
+        self.quirks.key_wait_mode = mode;
+        self
+    }
+
+    pub fn shift_mode(mut self, mode: ShiftMode) -> Self {
+        self.quirks.shift_mode = mode;
+        self
+    }
+
+    /// Memory size overriding the one of the quirks, MEMORY_SIZE (4KB) for most presets and
+    /// XO_MEMORY_SIZE (64KB) for XO-CHIP. Clamped between the space used by the interpreter
+    /// and the 64KB addressable by I
+    pub fn memory_size(mut self, size: usize) -> Self {
+        self.memory_size = Some(size);
+        self
+    }
+
+    /// Seed the random number generator to get reproducible runs
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Load the RPL user flags saved by previous runs of the ROM from dir,
+    /// VM::save_rpl_flags writes them back
+    pub fn flags_dir(mut self, dir: PathBuf) -> Self {
+        self.flags_dir = Some(dir);
+        self
+    }
+
+    /// Create the VM with the ROM loaded at the start address
+    pub fn build(self, rom: &[u8]) -> Result<VM, VmError> {
+        let memory_size = self.memory_size.unwrap_or(self.quirks.memory_size).clamp(START_ADDR, XO_MEMORY_SIZE);
+        let mut vm_mem = vec![0; memory_size];
+
+        if self.font.len() != FONT_SIZE {
+            return Err(VmError::InvalidFont { size: self.font.len(), expected: FONT_SIZE });
+        }
+        if rom.len() > vm_mem.len() - START_ADDR {
+            return Err(VmError::RomTooLarge { size: rom.len(), max: vm_mem.len() - START_ADDR });
+        }
+        vm_mem[FONT_ADDR..FONT_ADDR + FONT_SIZE].copy_from_slice(&self.font);
+        vm_mem[BIG_FONT_ADDR..BIG_FONT_ADDR + BIG_FONT_SIZE].copy_from_slice(&DEFAULT_BIG_FONT);
+        vm_mem[START_ADDR..START_ADDR + rom.len()].copy_from_slice(rom);
+
+        let flags_path = self.flags_dir.map(|dir| flags_path(&dir, rom));
+        let rpl_flags = match &flags_path {
+            Some(path) => read_flags(path)?.unwrap_or([0; 16]),
+            None => [0; 16]
+        };
+
+        let rng_seed = self.seed.unwrap_or_else(rand::random);
+
+        // Fill the first block: get_word_pos underflows on a generator that never generated
+        let mut rng = ChaCha20Rng::seed_from_u64(rng_seed);
+        rng.set_word_pos(0);
+
+        Ok(VM {
+            memory: vm_mem,
+            rom_size: rom.len(),
+            pc: START_ADDR,
+            regs: [0; 16],
+            stack: [0; 32],
+            stack_ptr: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+            input: [false; 16],
+            key_events: VecDeque::new(),
+            key_wait: None,
+            display: Framebuffer::default(),
+            quirks: Quirks { memory_size, ..self.quirks },
+            rng,
+            rng_seed,
+            i: 0,
+            rpl_flags,
+            flags_path,
+            planes: PLANE_1,
+            audio_pattern: [0; 16],
+            pitch: DEFAULT_PITCH,
+            state: true
+        })
+    }
+
+    /// Create the VM from a ROM file
+    pub fn build_from_file(self, file_path: PathBuf) -> Result<VM, VmError> {
+        let mut rom = vec![];
+
+        File::open(file_path)?.read_to_end(&mut rom)?;
+        self.build(&rom)
+    }
+}
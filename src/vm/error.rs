@@ -0,0 +1,96 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Errors raised while loading or running a program
+#[derive(Debug)]
+pub enum VmError {
+
+    /// Opcode at address addr does not match any instruction
+    UnknownOpcode { addr: usize, opcode: (u8, u8) },
+
+    /// Subroutine call with a full stack
+    StackOverflow { pc: usize },
+
+    /// Return from a subroutine with an empty stack
+    StackUnderflow { pc: usize },
+
+    /// Instruction at pc accessed memory outside of the VM memory
+    MemoryOutOfBounds { pc: usize, addr: usize },
+
+    /// ROM does not fit between the start address and the end of memory
+    RomTooLarge { size: usize, max: usize },
+
+    /// Program counter left the VM memory
+    PcOutOfRange { pc: usize },
+
+    /// FX75 or FX85 at pc with VX past the RPL user flags of the quirks
+    FlagOutOfRange { pc: usize, x: u8, count: usize },
+
+    /// Save state cannot be loaded
+    BadSaveState { reason: &'static str },
+
+    /// Font does not contain exactly 16 glyphs
+    InvalidFont { size: usize, expected: usize },
+
+    Io(io::Error)
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::UnknownOpcode { addr, opcode } => write!(
+                f,
+                "Unknown opcode 0x{:02X}{:02X} at 0x{:03X}",
+                opcode.0,
+                opcode.1,
+                addr
+            ),
+            VmError::StackOverflow { pc } => write!(f, "Stack overflow at 0x{:03X}", pc),
+            VmError::StackUnderflow { pc } => write!(f, "Stack underflow at 0x{:03X}", pc),
+            VmError::MemoryOutOfBounds { pc, addr } => write!(
+                f,
+                "Out of bounds memory access to 0x{:X} at 0x{:03X}",
+                addr,
+                pc
+            ),
+            VmError::RomTooLarge { size, max } => write!(
+                f,
+                "ROM too large ({} bytes, at most {} bytes)",
+                size,
+                max
+            ),
+            VmError::PcOutOfRange { pc } => write!(f, "Program counter out of range (0x{:X})", pc),
+            VmError::FlagOutOfRange { pc, x, count } => write!(
+                f,
+                "RPL user flag V{:X} out of range at 0x{:03X} ({} flags)",
+                x,
+                pc,
+                count
+            ),
+            VmError::BadSaveState { reason } => write!(f, "Bad save state: {}", reason),
+            VmError::InvalidFont { size, expected } => write!(
+                f,
+                "Invalid font ({} bytes, expected {} bytes)",
+                size,
+                expected
+            ),
+            VmError::Io(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VmError::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for VmError {
+    fn from(err: io::Error) -> Self {
+        VmError::Io(err)
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+
+use log::info;
+
+use crate::vm::{ VM, VmError };
+
+/// HP48 RPL user flags of SCHIP
+pub const SCHIP_FLAG_COUNT: usize = 8;
+
+/// RPL user flags of XO-CHIP
+pub const XO_FLAG_COUNT: usize = 16;
+
+/// FNV-1a hash of the ROM, identifies the ROM whatever its file name
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// File storing the RPL user flags of a ROM in dir
+pub fn flags_path(dir: &Path, rom: &[u8]) -> PathBuf {
+    dir.join(format!("{:016x}.flags", rom_hash(rom)))
+}
+
+/// Flags stored in path, None if the ROM never saved any
+pub fn read_flags(path: &Path) -> Result<Option<[u8; 16]>, VmError> {
+    match fs::read(path) {
+        Ok(data) => {
+            let mut flags = [0; 16];
+            let len = data.len().min(flags.len());
+            flags[..len].copy_from_slice(&data[..len]);
+            Ok(Some(flags))
+        },
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into())
+    }
+}
+
+impl VM {
+
+    /// Flags persisted across runs, set by the quirks
+    pub fn rpl_flag_count(&self) -> usize {
+        self.quirks.rpl_flag_count.min(self.rpl_flags.len())
+    }
+
+    pub fn set_rpl_flags(&mut self, flags: &[u8]) {
+        let len = flags.len().min(self.rpl_flags.len());
+        self.rpl_flags[..len].copy_from_slice(&flags[..len]);
+    }
+
+    /// Write the RPL user flags to the file they were loaded from. Nothing is
+    /// written for programs that never used FX75 so that the storage directory
+    /// only holds the ROMs saving high scores
+    pub fn save_rpl_flags(&self) -> Result<(), VmError> {
+        let path = match &self.flags_path {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        let flags = &self.rpl_flags[..self.rpl_flag_count()];
+        if flags.iter().all(|flag| *flag == 0) && !path.exists() {
+            return Ok(());
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, flags)?;
+        info!("RPL user flags saved to {}", path.display());
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::vm::{ VmBuilder, Quirks };
+
+    #[test]
+    fn test_rom_hash() {
+        assert_eq!(rom_hash(&[]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(rom_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_ne!(rom_hash(&[0x00, 0xE0]), rom_hash(&[0x00, 0xE0, 0x00]));
+    }
+
+    #[test]
+    fn test_persist_flags() {
+        let dir = env::temp_dir().join(format!("chip8-flags-{}", std::process::id()));
+        let rom = [0xF7, 0x75, 0x12, 0x02];
+
+        let mut vm = VmBuilder::new().flags_dir(dir.clone()).build(&rom).unwrap();
+        assert_eq!(vm.rpl_flags(), &[0; 16]);
+        vm.save_rpl_flags().unwrap();
+        assert!(!flags_path(&dir, &rom).exists());
+
+        vm.set_rpl_flags(&[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        vm.save_rpl_flags().unwrap();
+        assert_eq!(fs::read(flags_path(&dir, &rom)).unwrap(), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let vm = VmBuilder::new().flags_dir(dir.clone()).build(&rom).unwrap();
+        assert_eq!(&vm.rpl_flags()[..9], &[1, 2, 3, 4, 5, 6, 7, 8, 0]);
+        let vm = VmBuilder::new().flags_dir(dir.clone()).build(&rom[..2]).unwrap();
+        assert_eq!(vm.rpl_flags(), &[0; 16]);
+
+        let mut vm = VmBuilder::new().quirks(Quirks::xochip()).flags_dir(dir.clone()).build(&rom).unwrap();
+        vm.set_rpl_flags(&[1; 16]);
+        vm.save_rpl_flags().unwrap();
+        assert_eq!(fs::read(flags_path(&dir, &rom)).unwrap(), vec![1; 16]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
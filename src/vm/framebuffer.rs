@@ -0,0 +1,334 @@
+use crate::vm::{ DISPLAY_WIDTH, DISPLAY_HEIGHT };
+
+/// Display resolutions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    /// 64x32 pixels
+    Low,
+
+    /// 128x64 pixels (SCHIP)
+    High
+}
+
+impl Resolution {
+    pub fn width(self) -> usize {
+        match self {
+            Resolution::Low => DISPLAY_WIDTH,
+            Resolution::High => DISPLAY_WIDTH * 2
+        }
+    }
+
+    pub fn height(self) -> usize {
+        match self {
+            Resolution::Low => DISPLAY_HEIGHT,
+            Resolution::High => DISPLAY_HEIGHT * 2
+        }
+    }
+}
+
+/// Area of the display in pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize
+}
+
+impl Rect {
+
+    /// Smallest rectangle containing both rectangles
+    pub fn union(self, other: Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+
+        Rect { x, y, width: right - x, height: bottom - y }
+    }
+}
+
+/// Bitplanes of the display, XO-CHIP draws on one or both of them
+pub const PLANE_1: u8 = 0b01;
+pub const PLANE_2: u8 = 0b10;
+pub const ALL_PLANES: u8 = PLANE_1 | PLANE_2;
+
+/// Display of two bitplanes (4 colors) with tracking of the pixels changed since the last present.
+/// Programs which do not select planes only draw on the first one and get a monochrome display
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    resolution: Resolution,
+
+    /// Pixels colors from 0 to 3, one bit per plane, row by row from the top left corner
+    pixels: Vec<u8>,
+
+    /// Area changed since the last present
+    dirty: Option<Rect>
+}
+
+/// Displays are equal when they show the same pixels, whatever changed since the last present
+impl PartialEq for Framebuffer {
+    fn eq(&self, other: &Self) -> bool {
+        self.resolution == other.resolution && self.pixels == other.pixels
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Framebuffer::new(Resolution::Low)
+    }
+}
+
+impl Framebuffer {
+    pub fn new(resolution: Resolution) -> Self {
+        Framebuffer {
+            resolution,
+            pixels: vec![0; resolution.width() * resolution.height()],
+            dirty: None
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.resolution.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.resolution.height()
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    /// Switch resolution, the display is cleared
+    pub fn set_resolution(&mut self, resolution: Resolution) {
+        *self = Framebuffer::new(resolution);
+        self.mark_all_dirty();
+    }
+
+    /// Whether pixel at (x, y) is lit on any plane, false outside of the display
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.color(x, y) != 0
+    }
+
+    /// Color from 0 to 3 of pixel at (x, y), 0 outside of the display
+    pub fn color(&self, x: usize, y: usize) -> u8 {
+        if x >= self.width() || y >= self.height() {
+            return 0;
+        }
+        self.pixels[y * self.width() + x]
+    }
+
+    /// Light pixel at (x, y) on the first plane only or turn it off, ignored outside of the display
+    pub fn set(&mut self, x: usize, y: usize, on: bool) {
+        self.set_color(x, y, on as u8);
+    }
+
+    /// Set the color from 0 to 3 of pixel at (x, y), ignored outside of the display
+    pub fn set_color(&mut self, x: usize, y: usize, color: u8) {
+        if x >= self.width() || y >= self.height() {
+            return;
+        }
+        let idx = y * self.width() + x;
+        if self.pixels[idx] != color & ALL_PLANES {
+            self.pixels[idx] = color & ALL_PLANES;
+            self.mark_dirty(Rect { x, y, width: 1, height: 1 });
+        }
+    }
+
+    /// Flip pixel at (x, y) on the first plane, returns true if the pixel was on (collision)
+    pub fn xor(&mut self, x: usize, y: usize) -> bool {
+        self.xor_planes(x, y, PLANE_1)
+    }
+
+    /// Flip pixel at (x, y) on the given planes, returns true if it was on in one of them
+    pub fn xor_planes(&mut self, x: usize, y: usize, planes: u8) -> bool {
+        let color = self.color(x, y);
+        self.set_color(x, y, color ^ planes);
+        color & planes != 0
+    }
+
+    pub fn clear(&mut self) {
+        self.clear_planes(ALL_PLANES);
+    }
+
+    /// Turn off the given planes
+    pub fn clear_planes(&mut self, planes: u8) {
+        for pixel in self.pixels.iter_mut() {
+            *pixel &= !planes;
+        }
+        self.mark_all_dirty();
+    }
+
+    /// Move the pixels by (dx, dy), pixels leaving the display are lost
+    /// and the uncovered area is cleared
+    pub fn scroll(&mut self, dx: isize, dy: isize) {
+        self.scroll_planes(dx, dy, ALL_PLANES);
+    }
+
+    /// Scroll the given planes only
+    pub fn scroll_planes(&mut self, dx: isize, dy: isize, planes: u8) {
+        let width = self.width() as isize;
+        let height = self.height() as isize;
+        let mut pixels: Vec<u8> = self.pixels.iter().map(|pixel| pixel & !planes).collect();
+
+        for y in 0..height {
+            for x in 0..width {
+                let (src_x, src_y) = (x - dx, y - dy);
+                if src_x >= 0 && src_x < width && src_y >= 0 && src_y < height {
+                    pixels[(y * width + x) as usize] |= self.pixels[(src_y * width + src_x) as usize] & planes;
+                }
+            }
+        }
+        self.pixels = pixels;
+        self.mark_all_dirty();
+    }
+
+    /// Rows of pixels colors from top to bottom
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        self.pixels.chunks(self.width())
+    }
+
+    /// Area changed since the last call to clear_dirty, None if nothing changed
+    pub fn dirty(&self) -> Option<Rect> {
+        self.dirty
+    }
+
+    /// Reset the changed area, to be called once the display is presented
+    pub fn clear_dirty(&mut self) {
+        self.dirty = None;
+    }
+
+    /// RGBA bytes of the display, row by row, pixels lit on any plane use the foreground
+    pub fn to_rgba(&self, foreground: [u8; 4], background: [u8; 4]) -> Vec<u8> {
+        self.to_rgba_palette(&[background, foreground, foreground, foreground])
+    }
+
+    /// RGBA bytes of the display, row by row, using a color for each of the 4 pixel values
+    pub fn to_rgba_palette(&self, palette: &[[u8; 4]; 4]) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(self.pixels.len() * 4);
+
+        for pixel in self.pixels.iter() {
+            rgba.extend_from_slice(&palette[*pixel as usize]);
+        }
+        rgba
+    }
+
+    fn mark_dirty(&mut self, rect: Rect) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(rect),
+            None => rect
+        });
+    }
+
+    /// Mark the whole display as changed, forcing frontends to redraw it
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty = Some(Rect { x: 0, y: 0, width: self.width(), height: self.height() });
+    }
+
+    /// Move the pixels out, the display is empty until they are put back by set_pixels
+    pub(crate) fn take_pixels(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pixels)
+    }
+
+    pub(crate) fn set_pixels(&mut self, pixels: Vec<u8>) {
+        self.pixels = pixels;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pixels() {
+        let mut fb = Framebuffer::default();
+        fb.set(3, 4, true);
+
+        assert!(fb.get(3, 4));
+        assert!(!fb.get(4, 3));
+        assert!(!fb.get(64, 0));
+        assert!(fb.xor(3, 4));
+        assert!(!fb.xor(3, 4));
+        assert!(fb.get(3, 4));
+        assert_eq!(fb.rows().nth(4).unwrap()[3], 1);
+        assert_eq!(fb.rows().count(), 32);
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut fb = Framebuffer::new(Resolution::High);
+        fb.set(0, 0, true);
+        fb.set(127, 63, true);
+        fb.clear_dirty();
+
+        fb.scroll(4, 2);
+        assert!(fb.get(4, 2));
+        assert!(!fb.get(0, 0));
+        assert!(!fb.get(127, 63));
+        assert_eq!(fb.dirty(), Some(Rect { x: 0, y: 0, width: 128, height: 64 }));
+
+        fb.scroll(-4, 0);
+        assert!(fb.get(0, 2));
+        assert_eq!(fb.rows().flatten().filter(|pixel| **pixel != 0).count(), 1);
+    }
+
+    #[test]
+    fn test_planes() {
+        let mut fb = Framebuffer::default();
+        assert!(!fb.xor_planes(1, 1, PLANE_2));
+        assert!(!fb.xor_planes(1, 1, PLANE_1));
+        assert_eq!(fb.color(1, 1), 3);
+        assert!(fb.xor_planes(1, 1, PLANE_2));
+        assert_eq!(fb.color(1, 1), 1);
+
+        fb.set_color(2, 2, 3);
+        fb.scroll_planes(1, 0, PLANE_2);
+        assert_eq!((fb.color(2, 2), fb.color(3, 2)), (1, 2));
+        fb.clear_planes(PLANE_1);
+        assert_eq!((fb.color(1, 1), fb.color(2, 2), fb.color(3, 2)), (0, 0, 2));
+
+        let palette = [[0; 4], [1; 4], [2; 4], [3; 4]];
+        assert_eq!(&fb.to_rgba_palette(&palette)[(2 * 64 + 3) * 4..][..4], &[2; 4]);
+    }
+
+    #[test]
+    fn test_dirty_rect() {
+        let mut fb = Framebuffer::default();
+        assert_eq!(fb.dirty(), None);
+
+        fb.set(2, 3, true);
+        fb.xor(10, 1);
+        assert_eq!(fb.dirty(), Some(Rect { x: 2, y: 1, width: 9, height: 3 }));
+
+        fb.clear_dirty();
+        fb.set(2, 3, true);
+        assert_eq!(fb.dirty(), None);
+
+        fb.clear();
+        assert_eq!(fb.dirty(), Some(Rect { x: 0, y: 0, width: 64, height: 32 }));
+    }
+
+    #[test]
+    fn test_resolution() {
+        let mut fb = Framebuffer::default();
+        fb.set(1, 1, true);
+        fb.set_resolution(Resolution::High);
+
+        assert_eq!((fb.width(), fb.height()), (128, 64));
+        assert!(!fb.get(1, 1));
+        fb.set(127, 63, true);
+        assert!(fb.get(127, 63));
+    }
+
+    #[test]
+    fn test_to_rgba() {
+        let mut fb = Framebuffer::default();
+        fb.set(1, 0, true);
+        let rgba = fb.to_rgba([255, 255, 255, 255], [0, 0, 0, 255]);
+
+        assert_eq!(rgba.len(), 64 * 32 * 4);
+        assert_eq!(&rgba[..8], &[0, 0, 0, 255, 255, 255, 255, 255]);
+    }
+}
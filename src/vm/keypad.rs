@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+/// Keypad event sent by a frontend, key from 0 to F
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyEvent {
+    Pressed(u8),
+    Released(u8)
+}
+
+/// Mapping from host keyboard characters to the 16 keys keypad
+///
+/// Default layout (QWERTY):
+///
+/// ```text
+///  Keyboard       CHIP-8
+///  1 2 3 4        1 2 3 C
+///  Q W E R   ->   4 5 6 D
+///  A S D F        7 8 9 E
+///  Z X C V        A 0 B F
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keymap {
+    keys: HashMap<char, u8>
+}
+
+/// Host keys of the default layout, ordered from key 0 to F
+const QWERTY_LAYOUT: &str = "x123qweasdzc4rfv";
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::from_layout(QWERTY_LAYOUT).unwrap()
+    }
+}
+
+impl Keymap {
+
+    /// Keymap without any binding
+    pub fn empty() -> Self {
+        Keymap { keys: HashMap::new() }
+    }
+
+    /// Create a keymap from 16 host keys ordered from key 0 to F
+    pub fn from_layout(layout: &str) -> Option<Self> {
+        let mut keymap = Keymap::empty();
+
+        if layout.chars().count() != 16 {
+            return None;
+        }
+        for (key, host) in layout.chars().enumerate() {
+            keymap.bind(host, key as u8);
+        }
+        Some(keymap)
+    }
+
+    /// Bind a host key to a keypad key, host keys are case insensitive
+    pub fn bind(&mut self, host: char, key: u8) {
+        self.keys.insert(host.to_ascii_lowercase(), key & 0xF);
+    }
+
+    /// Keypad key bound to a host key
+    pub fn get(&self, host: char) -> Option<u8> {
+        self.keys.get(&host.to_ascii_lowercase()).cloned()
+    }
+
+    /// Keypad event for a host key press or release
+    pub fn event(&self, host: char, pressed: bool) -> Option<KeyEvent> {
+        self.get(host).map(|key| {
+            if pressed {
+                KeyEvent::Pressed(key)
+            } else {
+                KeyEvent::Released(key)
+            }
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_keymap() {
+        let keymap = Keymap::default();
+
+        assert_eq!(keymap.get('1'), Some(0x1));
+        assert_eq!(keymap.get('4'), Some(0xC));
+        assert_eq!(keymap.get('X'), Some(0x0));
+        assert_eq!(keymap.get('v'), Some(0xF));
+        assert_eq!(keymap.get('p'), None);
+        assert_eq!(keymap.event('r', false), Some(KeyEvent::Released(0xD)));
+    }
+
+    #[test]
+    fn test_keymap_from_layout() {
+        let keymap = Keymap::from_layout("0123456789abcdef").unwrap();
+
+        assert_eq!(keymap.get('a'), Some(0xA));
+        assert_eq!(keymap.get('7'), Some(0x7));
+        assert!(Keymap::from_layout("0123").is_none());
+    }
+}
@@ -1,23 +1,76 @@
 use std::path::PathBuf;
 use std::convert::TryFrom;
-use std::fs::File;
-use std::io::{ self, Read, ErrorKind };
+use std::collections::VecDeque;
 use std::fmt;
 
-use log::{ error };
+use log::{ error, info };
+use rand_chacha::ChaCha20Rng;
 
 use crate::instructions::{ Instruction };
 
 mod vm_instructions;
 use vm_instructions::*;
 
-const START_ADDR: usize = 0x200;
+mod error;
+pub use error::VmError;
+
+mod builder;
+pub use builder::VmBuilder;
+
+pub mod font;
+
+mod framebuffer;
+pub use framebuffer::{ Framebuffer, Rect, Resolution, PLANE_1, PLANE_2, ALL_PLANES };
+
+pub mod quirks;
+pub use quirks::{ Quirks, DrawMode, KeyWaitMode, ShiftMode };
+
+mod state;
+pub use state::Snapshot;
+
+mod flags;
+pub use flags::{ rom_hash, flags_path, SCHIP_FLAG_COUNT, XO_FLAG_COUNT };
+
+mod keypad;
+pub use keypad::{ KeyEvent, Keymap };
+
+/// Address where ROMs are loaded and execution starts
+pub const START_ADDR: usize = 0x200;
+
+/// Size of the VM memory in bytes
+pub const MEMORY_SIZE: usize = 4096;
+
+/// Size of the XO-CHIP memory in bytes, addressable with F000 NNNN
+pub const XO_MEMORY_SIZE: usize = 0x10000;
+
+/// Default pitch of the XO-CHIP audio pattern, played at 4000Hz
+pub const DEFAULT_PITCH: u8 = 64;
+
+/// Frequency in Hz of the delay and sound timers
+pub const TIMER_FREQUENCY: u32 = 60;
+
+/// Display low resolution in pixels
+pub const DISPLAY_WIDTH: usize = 64;
+pub const DISPLAY_HEIGHT: usize = 32;
+
+/// Pending FX0A instruction
+struct KeyWait {
+
+    /// Register receiving the key
+    x: u8,
+
+    /// Key pressed since the wait started, waiting for its release
+    pressed: Option<u8>
+}
 
 #[allow(non_snake_case)]
 pub struct VM {
 
-    /// 4KB VM memory
-    memory: [u8; 4096],
+    /// VM memory, 4KB or 64KB for XO-CHIP
+    memory: Vec<u8>,
+
+    /// Size of the ROM loaded at START_ADDR
+    rom_size: usize,
     pc: usize,
 
     /// VM Registers V0 to VF,  VF = carry flag
@@ -34,75 +87,352 @@ pub struct VM {
     /// 16 key keyboard from 0 to F
     input: [bool; 16],
 
-    /// 64x32 pixels display from (OxO, OxO) to (Ox3f, 0x1f)
-    display: [[bool; 64]; 32],
+    /// Key events waiting to be applied to the keyboard, one per instruction
+    key_events: VecDeque<KeyEvent>,
+
+    /// Set while execution is suspended by FX0A
+    key_wait: Option<KeyWait>,
+
+    /// Display, 64x32 pixels from (OxO, OxO) to (Ox3f, 0x1f) in low resolution
+    display: Framebuffer,
+
+    /// Interpreter specific behaviours
+    quirks: Quirks,
 
     /// 16bits address register (void pointer)
     i: u16,
 
+    /// HP48 RPL user flags saved and loaded by FX75 and FX85
+    rpl_flags: [u8; 16],
+
+    /// File the RPL user flags are persisted to, None when they are not persisted
+    flags_path: Option<PathBuf>,
+
+    /// Bitplanes drawn, cleared and scrolled (XO-CHIP FN01)
+    planes: u8,
+
+    /// 1 bit per sample audio pattern played while the sound timer is active (XO-CHIP F002)
+    audio_pattern: [u8; 16],
+
+    /// Playback pitch of the audio pattern (XO-CHIP FX3A)
+    pitch: u8,
+
+    /// Random number generator used by CXNN
+    rng: ChaCha20Rng,
+
+    /// Seed of rng, saved with its position in the stream by snapshots
+    rng_seed: u64,
+
     /// State of vm (on/off)
     state: bool
 }
 
 
 impl VM {
-    pub fn get_program(&self) -> Vec<String> {
-        let len = self.memory.len() - 1;
+    /// Disassembly of the ROM in the syntax of the assembler with the address of each
+    /// instruction. Words which are not instructions, 0x0000 included, are written as dw
+    /// data and a last odd byte as db, so that assembling the listing gives back the ROM
+    pub fn get_program(&self) -> Vec<(usize, String)> {
         let mut program = vec![];
-        for x in (START_ADDR..len).step_by(2) {
-            let instruction = Instruction::from((self.memory[x], self.memory[x + 1]));
+        let end = START_ADDR + self.rom_size;
+        let mut x = START_ADDR;
+        while x + 1 < end {
+            let instruction = Instruction::decode(&self.memory[x..end]);
 
-            if instruction == Instruction::EndOfProgram {
-                break;
+            match instruction {
+                Instruction::EndOfProgram | Instruction::UnknownInstruction => {
+                    program.push((x, format!("dw 0x{:02X}{:02X}", self.memory[x], self.memory[x + 1])));
+                    x += 2;
+                },
+                _ => {
+                    program.push((x, instruction.to_asm()));
+                    x += instruction.size();
+                }
             }
-            program.push(instruction.to_asm());
+        }
+        if x < end {
+            program.push((x, format!("db 0x{:02X}", self.memory[x])));
         }
         program
     }
 
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Address register I
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    /// Registers V0 to VF
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.regs
+    }
+
+    /// Return addresses of the subroutine calls in progress, innermost last
+    pub fn stack(&self) -> &[usize] {
+        &self.stack[..self.stack_ptr]
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn display(&self) -> &Framebuffer {
+        &self.display
+    }
+
+    /// Reset the display changed area once presented by a frontend
+    pub fn clear_display_dirty(&mut self) {
+        self.display.clear_dirty();
+    }
+
+    /// Bitplanes selected by FN01
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    /// 16 bytes audio pattern, 128 samples of 1 bit played most significant bit first
+    pub fn audio_pattern(&self) -> &[u8; 16] {
+        &self.audio_pattern
+    }
+
+    /// Audio pattern samples played per second, 4000 * 2 ^ ((pitch - 64) / 48)
+    pub fn playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+
+    /// HP48 RPL user flags (FX75, FX85)
+    pub fn rpl_flags(&self) -> &[u8; 16] {
+        &self.rpl_flags
+    }
+
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn set_draw_mode(&mut self, mode: DrawMode) {
+        self.quirks.draw_mode = mode;
+    }
+
+    /// Set key (0 to F) as pressed
+    pub fn press_key(&mut self, key: u8) {
+        let idx = key as usize;
+        if idx >= self.input.len() {
+            return;
+        }
+        self.input[idx] = true;
+
+        let mode = self.quirks.key_wait_mode;
+        if let Some(wait) = self.key_wait.as_mut() {
+            match mode {
+                KeyWaitMode::Press => self.resume_key_wait(key),
+                KeyWaitMode::Release => wait.pressed = Some(key)
+            }
+        }
+    }
+
+    /// Set key (0 to F) as released
+    pub fn release_key(&mut self, key: u8) {
+        let idx = key as usize;
+        if idx >= self.input.len() {
+            return;
+        }
+        self.input[idx] = false;
+
+        if let Some(KeyWait { pressed: Some(pressed), .. }) = self.key_wait {
+            if pressed == key && self.quirks.key_wait_mode == KeyWaitMode::Release {
+                self.resume_key_wait(key);
+            }
+        }
+    }
+
+    /// Set the state of the whole keyboard, key i being pressed if keys[i] is set
+    pub fn set_keys(&mut self, keys: &[bool; 16]) {
+        for (key, pressed) in keys.iter().enumerate() {
+            if *pressed && !self.input[key] {
+                self.press_key(key as u8);
+            } else if !*pressed && self.input[key] {
+                self.release_key(key as u8);
+            }
+        }
+    }
+
+    pub fn is_key_pressed(&self, key: u8) -> bool {
+        self.input.get(key as usize).cloned().unwrap_or(false)
+    }
+
+    /// Queue a key event, queued events are applied one per executed
+    /// instruction so that short presses are still seen by the program
+    pub fn push_key_event(&mut self, event: KeyEvent) {
+        self.key_events.push_back(event);
+    }
+
+    fn apply_next_key_event(&mut self) {
+        match self.key_events.pop_front() {
+            Some(KeyEvent::Pressed(key)) => self.press_key(key),
+            Some(KeyEvent::Released(key)) => self.release_key(key),
+            None => ()
+        }
+    }
+
+    /// Whether execution is suspended until a key event (FX0A)
+    pub fn is_awaiting_key(&self) -> bool {
+        self.key_wait.is_some()
+    }
+
+    fn resume_key_wait(&mut self, key: u8) {
+        if let Some(wait) = self.key_wait.take() {
+            self.regs[wait.x as usize] = key;
+        }
+    }
+
+    /// Decrement delay and sound timers, to be called at TIMER_FREQUENCY
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    /// Whether the buzzer should currently sound
+    pub fn is_sound_active(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    /// Execute one 60Hz frame: up to cycles_per_frame instructions then a timers tick.
+    /// Returns the number of cycles run
+    pub fn run_frame(&mut self, cycles_per_frame: usize) -> Result<usize, VmError> {
+        let mut cycles = 0;
+
+        while cycles < cycles_per_frame && self.run() {
+            let instruction = self.execute_next()?;
+            cycles += 1;
+
+            // With the display wait quirk a sprite ends the frame
+            if let Instruction::Draw { .. } = instruction {
+                if self.quirks.display_wait {
+                    break;
+                }
+            }
+        }
+        self.tick_timers();
+        Ok(cycles)
+    }
+
     pub fn run(&self) -> bool {
-        return self.state;
+        self.state
     }
 
-    pub fn execute_next(&mut self) -> Result<Instruction, io::Error> {
-        let bytes = (self.memory[self.pc], self.memory[self.pc + 1]);
-        let instruction = Instruction::from(bytes);
+    pub fn execute_next(&mut self) -> Result<Instruction, VmError> {
+        self.apply_next_key_event();
+        if let Some(wait) = &self.key_wait {
+            return Ok(Instruction::AwaitKeyPressed { x: wait.x });
+        }
+
+        let addr = self.pc;
+        if addr + 1 >= self.memory.len() {
+            return Err(VmError::PcOutOfRange { pc: addr });
+        }
+        let bytes = (self.memory[addr], self.memory[addr + 1]);
+        let instruction = Instruction::decode(&self.memory[addr..]);
 
         if instruction == Instruction::EndOfProgram {
             self.state = false;
-            println!("end");
+            info!("End of program at 0x{:03X}", addr);
             return Ok(instruction);
         }
-        self.execute(&instruction, bytes)?;
-        self.pc += 2;
+
+        // PC points to the next instruction during execution,
+        // on error it is restored to the faulting instruction
+        self.pc += instruction.size();
+        if let Err(err) = self.execute(&instruction, bytes) {
+            self.pc = addr;
+            return Err(err);
+        }
         Ok(instruction)
     }
 
-    fn execute(&mut self, instruction: &Instruction, bytes: (u8, u8)) -> Result<(), io::Error> {
+    /// Address of the instruction being executed
+    fn current_addr(&self) -> usize {
+        self.pc.wrapping_sub(2)
+    }
+
+    fn read_memory(&self, addr: usize) -> Result<u8, VmError> {
+        match self.memory.get(addr) {
+            Some(byte) => Ok(*byte),
+            None => Err(VmError::MemoryOutOfBounds { pc: self.current_addr(), addr })
+        }
+    }
+
+    /// Fails on the first address of addr..addr + len outside of the memory, so that
+    /// instructions accessing several bytes check them all before writing any
+    fn check_memory_range(&self, addr: usize, len: usize) -> Result<(), VmError> {
+        if addr + len > self.memory.len() {
+            return Err(VmError::MemoryOutOfBounds { pc: self.current_addr(), addr: addr.max(self.memory.len()) });
+        }
+        Ok(())
+    }
+
+    fn write_memory(&mut self, addr: usize, value: u8) -> Result<(), VmError> {
+        let pc = self.current_addr();
+        match self.memory.get_mut(addr) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            },
+            None => Err(VmError::MemoryOutOfBounds { pc, addr })
+        }
+    }
+
+    fn execute(&mut self, instruction: &Instruction, bytes: (u8, u8)) -> Result<(), VmError> {
         match *instruction {
             Instruction::Clear => self.clear(),
-            Instruction::Return => self.return_subroutine(),
+            Instruction::Return => self.return_subroutine()?,
+            Instruction::ScrollDown { n } => self.scroll(0, n as isize),
+            Instruction::ScrollRight => self.scroll(4, 0),
+            Instruction::ScrollLeft => self.scroll(-4, 0),
+            Instruction::Exit => self.exit(),
+            Instruction::LowResolution => self.set_resolution(Resolution::Low),
+            Instruction::HighResolution => self.set_resolution(Resolution::High),
             Instruction::Goto { addr } => self.goto(addr),
-            Instruction::CallSubroutine { addr } => self.call_subroutine(addr),
+            Instruction::CallSubroutine { addr } => self.call_subroutine(addr)?,
             Instruction::SkipEqualU8 { x, value } => self.skip_equal(self.regs[x as usize], value),
             Instruction::SkipNotEqualU8 { x, value } => self.skip_not_equal(self.regs[x as usize], value),
             Instruction::SkipEqualReg { x, y } => self.skip_equal(self.regs[x as usize], self.regs[y as usize]),
+            Instruction::SaveRange { x, y } => self.save_range(x, y)?,
+            Instruction::LoadRange { x, y } => self.load_range(x, y)?,
             Instruction::SetFromU8 { x, value } => self.load(x, value),
             Instruction::AddU8 { x, value } => self.add(x, value),
             Instruction::SetFromReg { x, y } => self.load(x, self.regs[y as usize]),
             Instruction::OrReg { x, y } => self.or(x, y),
             Instruction::AndReg { x, y } => self.and(x, y),
             Instruction::XorReg { x, y } => self.xor(x, y),
-            Instruction::AddReg { x, y } => self.add(x, self.regs[y as usize]),
+            Instruction::AddReg { x, y } => self.add_reg(x, y),
             Instruction::SubReg { x, y } => self.sub(x, y),
             Instruction::RevSubReg { x, y } => self.revsub(x, y),
-            Instruction::ShiftRight { x } => self.shift_right(x),
-            Instruction::ShiftLeft { x } => self.shift_left(x),
+            Instruction::ShiftRight { x, y } => self.shift_right(x, y),
+            Instruction::ShiftLeft { x, y } => self.shift_left(x, y),
             Instruction::SkipNotEqualReg { x, y } => self.skip_not_equal(self.regs[x as usize], self.regs[y as usize]),
             Instruction::StoreAddress { addr } => self.store_address(addr),
+            Instruction::LongStoreAddress { addr } => self.long_store_address(addr),
+            Instruction::SelectPlanes { n } => self.select_planes(n),
+            Instruction::LoadAudioPattern => self.load_audio_pattern()?,
+            Instruction::SetPitch { x } => self.set_pitch(x),
             Instruction::JumpToAddress { addr } => self.jump(addr),
             Instruction::Rand { x, value } => self.rand(x, value),
-            Instruction::Draw { x, y, n } => self.draw(x, y, n),
+            Instruction::Draw { x, y, n } => self.draw(x, y, n)?,
             Instruction::SkipIfKeyPressed { x } => self.skip_key_pressed(x),
             Instruction::SkipIfNotKeyPressed { x } => self.skip_not_key_pressed(x),
             Instruction::SetDelayTimer { x } => self.set_delay_timer(x),
@@ -111,9 +441,12 @@ impl VM {
             Instruction::AwaitKeyPressed { x } => self.wait_key_pressed(x),
             Instruction::AddToI { x } => self.increment_addr_reg(x),
             Instruction::SetIToSpriteAddress { x } => self.store_sprite_addr(x),
-            Instruction::StoreAtIAsDecimal { x } => self.bcd(x),
-            Instruction::DumpToMemory { x } => self.register_dump(x),
-            Instruction::LoadFromMemory { x } => self.register_load(x),
+            Instruction::SetIToBigSpriteAddress { x } => self.store_big_sprite_addr(x),
+            Instruction::StoreAtIAsDecimal { x } => self.bcd(x)?,
+            Instruction::DumpToMemory { x } => self.register_dump(x)?,
+            Instruction::LoadFromMemory { x } => self.register_load(x)?,
+            Instruction::StoreFlags { x } => self.store_flags(x)?,
+            Instruction::LoadFlags { x } => self.load_flags(x)?,
             _ => {
                 error!(
                     "Error: (0x{:X}{:X} -> {}) Bad instruction",
@@ -121,10 +454,7 @@ impl VM {
                     bytes.1,
                     instruction
                 );
-                return Err(io::Error::new(
-                    ErrorKind::Interrupted,
-                    "Bad instruction"
-                ));
+                return Err(VmError::UnknownOpcode { addr: self.current_addr(), opcode: bytes });
             }
         }
         Ok(())
@@ -147,30 +477,18 @@ impl fmt::Debug for VM {
 }
 
 /// ROM loader
-impl TryFrom<PathBuf> for VM {
-    type Error = io::Error;
-
-    fn try_from(file_path: PathBuf) -> io::Result<Self> {
-        let file = File::open(file_path)?;
-        let mut vm_mem = [0; 4096];
-
-        for (i, byte) in file.bytes().enumerate() {
-            vm_mem[START_ADDR + i] = byte?;
-        }
-
-        Ok(VM {
-            memory: vm_mem,
-            pc: START_ADDR,
-            regs: [0; 16],
-            stack: [0; 32],
-            stack_ptr: 0,
-            delay_timer: 0,
-            sound_timer: 0,
-            input: [false; 16],
-            display: [[false; 64]; 32],
-            i: 0,
-            state: true
-        })
+impl TryFrom<&[u8]> for VM {
+    type Error = VmError;
+
+    fn try_from(rom: &[u8]) -> Result<Self, VmError> {
+        VmBuilder::new().build(rom)
     }
 }
 
+impl TryFrom<PathBuf> for VM {
+    type Error = VmError;
+
+    fn try_from(file_path: PathBuf) -> Result<Self, VmError> {
+        VmBuilder::new().build_from_file(file_path)
+    }
+}
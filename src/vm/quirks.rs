@@ -0,0 +1,136 @@
+use crate::vm::{ MEMORY_SIZE, XO_MEMORY_SIZE, SCHIP_FLAG_COUNT, XO_FLAG_COUNT };
+
+/// Behaviour of sprites drawn across the edges of the display
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DrawMode {
+    /// Pixels going past an edge reappear on the opposite side
+    Wrap,
+
+    /// Pixels going past an edge are discarded
+    Clip
+}
+
+/// Key event completing FX0A (await key pressed)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyWaitMode {
+    /// Resume as soon as a key is pressed
+    Press,
+
+    /// Resume once a key has been pressed then released (COSMAC VIP)
+    Release
+}
+
+/// Source register of the 8XY6 and 8XYE shifts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShiftMode {
+    /// VY is shifted and the result stored in VX (COSMAC VIP)
+    FromVy,
+
+    /// VX is shifted in place and VY ignored (CHIP-48, SCHIP)
+    InPlace
+}
+
+/// Interpreter specific behaviours ROMs may depend on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+
+    /// FX55 and FX65 leave I pointing after the last register (I += X + 1)
+    pub load_store_increment_i: bool,
+
+    /// BNNN jumps to NNN + VX, X being the highest nibble of NNN, instead of NNN + V0
+    pub jump_uses_vx: bool,
+
+    /// 8XY1, 8XY2 and 8XY3 reset VF to 0
+    pub logic_resets_vf: bool,
+
+    /// DXYN waits for the next frame, at most one sprite is drawn per frame
+    pub display_wait: bool,
+
+    /// Sprite behaviour on the display edges
+    pub draw_mode: DrawMode,
+
+    /// Source register of the shift instructions
+    pub shift_mode: ShiftMode,
+
+    /// Key event completing FX0A
+    pub key_wait_mode: KeyWaitMode,
+
+    /// Memory size in bytes of the VMs built with these quirks, 64KB for XO-CHIP
+    pub memory_size: usize,
+
+    /// RPL user flags persisted across runs, 8 for SCHIP and 16 for XO-CHIP
+    pub rpl_flag_count: usize
+}
+
+/// Names of the presets accepted by Quirks::from_preset
+pub const PRESETS: [&str; 4] = ["vip", "chip48", "schip", "xochip"];
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::vip()
+    }
+}
+
+impl Quirks {
+
+    /// Original COSMAC VIP interpreter
+    pub fn vip() -> Self {
+        Quirks {
+            load_store_increment_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            display_wait: true,
+            draw_mode: DrawMode::Clip,
+            shift_mode: ShiftMode::FromVy,
+            key_wait_mode: KeyWaitMode::Release,
+            memory_size: MEMORY_SIZE,
+            rpl_flag_count: SCHIP_FLAG_COUNT
+        }
+    }
+
+    /// CHIP-48 interpreter for the HP48 calculators
+    pub fn chip48() -> Self {
+        Quirks {
+            load_store_increment_i: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            display_wait: false,
+            draw_mode: DrawMode::Clip,
+            shift_mode: ShiftMode::InPlace,
+            key_wait_mode: KeyWaitMode::Release,
+            memory_size: MEMORY_SIZE,
+            rpl_flag_count: SCHIP_FLAG_COUNT
+        }
+    }
+
+    /// SUPER-CHIP 1.1
+    pub fn schip() -> Self {
+        Quirks::chip48()
+    }
+
+    /// XO-CHIP, as implemented by Octo
+    pub fn xochip() -> Self {
+        Quirks {
+            load_store_increment_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            display_wait: false,
+            draw_mode: DrawMode::Wrap,
+            shift_mode: ShiftMode::FromVy,
+            key_wait_mode: KeyWaitMode::Release,
+            memory_size: XO_MEMORY_SIZE,
+            rpl_flag_count: XO_FLAG_COUNT
+        }
+    }
+
+    /// Preset from its name (see PRESETS), case insensitive
+    pub fn from_preset(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "vip" | "chip8" => Some(Quirks::vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" => Some(Quirks::schip()),
+            "xochip" => Some(Quirks::xochip()),
+            _ => None
+        }
+    }
+}
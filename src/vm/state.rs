@@ -0,0 +1,413 @@
+use std::io::{ Read, Write };
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+use crate::vm::{ VM, VmError, KeyWait, Quirks, DrawMode, ShiftMode, KeyWaitMode, Framebuffer, Resolution };
+use crate::vm::{ PLANE_1, PLANE_2, SCHIP_FLAG_COUNT, XO_FLAG_COUNT };
+
+/// Save state header
+const MAGIC: &[u8; 4] = b"CH8S";
+
+/// Save state format version, to be bumped on any layout change
+const VERSION: u8 = 5;
+
+/// Complete VM state, pending key events excepted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub memory: Vec<u8>,
+    pub pc: usize,
+    pub regs: [u8; 16],
+    pub stack: [usize; 32],
+    pub stack_ptr: usize,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub input: [bool; 16],
+
+    /// Pending FX0A: register and key pressed since the wait started
+    pub key_wait: Option<(u8, Option<u8>)>,
+    pub display: Framebuffer,
+    pub quirks: Quirks,
+    pub i: u16,
+    pub rpl_flags: [u8; 16],
+    pub planes: u8,
+    pub audio_pattern: [u8; 16],
+    pub pitch: u8,
+
+    /// Seed and position in its stream, in 32 bits words, of the random number generator
+    pub rng_seed: u64,
+    pub rng_word_pos: u128,
+    pub state: bool
+}
+
+impl VM {
+
+    /// Capture the VM state, restored copies produce the same random numbers as the VM
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            memory: self.memory.to_vec(),
+            pc: self.pc,
+            regs: self.regs,
+            stack: self.stack,
+            stack_ptr: self.stack_ptr,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            input: self.input,
+            key_wait: self.key_wait.as_ref().map(|wait| (wait.x, wait.pressed)),
+            display: self.display.clone(),
+            quirks: self.quirks,
+            i: self.i,
+            rpl_flags: self.rpl_flags,
+            planes: self.planes,
+            audio_pattern: self.audio_pattern,
+            pitch: self.pitch,
+            rng_seed: self.rng_seed,
+            rng_word_pos: self.rng.get_word_pos(),
+            state: self.state
+        }
+    }
+
+    /// Restore a state captured by snapshot, pending key events are dropped. The VM is
+    /// left untouched when the snapshot memory size differs from the VM one
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), VmError> {
+        if snapshot.memory.len() != self.memory.len() {
+            return Err(VmError::BadSaveState { reason: "memory size mismatch" });
+        }
+        self.memory.copy_from_slice(&snapshot.memory);
+        self.pc = snapshot.pc;
+        self.regs = snapshot.regs;
+        self.stack = snapshot.stack;
+        self.stack_ptr = snapshot.stack_ptr;
+        self.delay_timer = snapshot.delay_timer;
+        self.sound_timer = snapshot.sound_timer;
+        self.input = snapshot.input;
+        self.key_events.clear();
+        self.key_wait = snapshot.key_wait.map(|(x, pressed)| KeyWait { x, pressed });
+        self.display = snapshot.display.clone();
+        self.display.mark_all_dirty();
+        self.quirks = snapshot.quirks;
+        self.i = snapshot.i;
+        self.rpl_flags = snapshot.rpl_flags;
+        self.planes = snapshot.planes;
+        self.audio_pattern = snapshot.audio_pattern;
+        self.pitch = snapshot.pitch;
+        self.rng = ChaCha20Rng::seed_from_u64(snapshot.rng_seed);
+        self.rng.set_word_pos(snapshot.rng_word_pos);
+        self.rng_seed = snapshot.rng_seed;
+        self.state = snapshot.state;
+        Ok(())
+    }
+
+    /// Write the VM state: magic header, version, state and checksum
+    pub fn save_state<W: Write>(&self, out: &mut W) -> Result<(), VmError> {
+        let data = encode(&self.snapshot());
+        out.write_all(&data)?;
+        out.write_all(&adler32(&data).to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Load a state written by save_state, the VM is left untouched on error
+    pub fn load_state<R: Read>(&mut self, input: &mut R) -> Result<(), VmError> {
+        let mut data = vec![];
+        input.read_to_end(&mut data)?;
+
+        if data.len() < MAGIC.len() + 1 + 4 || &data[..MAGIC.len()] != MAGIC {
+            return Err(VmError::BadSaveState { reason: "not a save state" });
+        }
+        if data[MAGIC.len()] != VERSION {
+            return Err(VmError::BadSaveState { reason: "unsupported version" });
+        }
+        let (data, checksum) = data.split_at(data.len() - 4);
+        if adler32(data).to_le_bytes() != checksum {
+            return Err(VmError::BadSaveState { reason: "checksum mismatch" });
+        }
+
+        let snapshot = decode(&data[MAGIC.len() + 1..])
+            .ok_or(VmError::BadSaveState { reason: "corrupted state" })?;
+        self.restore(&snapshot)
+    }
+}
+
+fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    let quirks = &snapshot.quirks;
+
+    data.push(VERSION);
+    data.extend_from_slice(&(snapshot.memory.len() as u32).to_le_bytes());
+    data.extend_from_slice(&snapshot.memory);
+    data.extend_from_slice(&(snapshot.pc as u16).to_le_bytes());
+    data.extend_from_slice(&snapshot.regs);
+    for addr in snapshot.stack.iter() {
+        data.extend_from_slice(&(*addr as u16).to_le_bytes());
+    }
+    data.push(snapshot.stack_ptr as u8);
+    data.push(snapshot.delay_timer);
+    data.push(snapshot.sound_timer);
+    data.extend(snapshot.input.iter().map(|key| *key as u8));
+    match snapshot.key_wait {
+        Some((x, pressed)) => data.extend_from_slice(&[1, x, pressed.is_some() as u8, pressed.unwrap_or(0)]),
+        None => data.extend_from_slice(&[0; 4])
+    }
+
+    data.push((snapshot.display.resolution() == Resolution::High) as u8);
+    for plane in [PLANE_1, PLANE_2].iter() {
+        for row in snapshot.display.rows() {
+            for byte in row.chunks(8) {
+                data.push(byte.iter().fold(0, |acc, pixel| (acc << 1) | (pixel & plane != 0) as u8));
+            }
+        }
+    }
+
+    data.push(
+        quirks.load_store_increment_i as u8
+            | (quirks.jump_uses_vx as u8) << 1
+            | (quirks.logic_resets_vf as u8) << 2
+            | (quirks.display_wait as u8) << 3
+            | ((quirks.draw_mode == DrawMode::Wrap) as u8) << 4
+            | ((quirks.shift_mode == ShiftMode::InPlace) as u8) << 5
+            | ((quirks.key_wait_mode == KeyWaitMode::Press) as u8) << 6
+            | ((quirks.rpl_flag_count == XO_FLAG_COUNT) as u8) << 7
+    );
+    data.extend_from_slice(&snapshot.i.to_le_bytes());
+    data.extend_from_slice(&snapshot.rpl_flags);
+    data.push(snapshot.planes);
+    data.extend_from_slice(&snapshot.audio_pattern);
+    data.push(snapshot.pitch);
+    data.extend_from_slice(&snapshot.rng_seed.to_le_bytes());
+    data.extend_from_slice(&snapshot.rng_word_pos.to_le_bytes());
+    data.push(snapshot.state as u8);
+    data
+}
+
+/// Cursor over the state bytes
+struct Reader<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (bytes, data) = self.data.split_at(len);
+        self.data = data;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        self.u8().map(|b| b != 0)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Some(u64::from_le_bytes(bytes))
+    }
+}
+
+fn decode(data: &[u8]) -> Option<Snapshot> {
+    let mut reader = Reader { data };
+    let memory_size = reader.u32()? as usize;
+    let memory = reader.bytes(memory_size)?.to_vec();
+    let pc = reader.u16()? as usize;
+
+    let mut regs = [0; 16];
+    regs.copy_from_slice(reader.bytes(16)?);
+    let mut stack = [0; 32];
+    for addr in stack.iter_mut() {
+        *addr = reader.u16()? as usize;
+    }
+    let stack_ptr = reader.u8()? as usize;
+    if stack_ptr > stack.len() {
+        return None;
+    }
+    let delay_timer = reader.u8()?;
+    let sound_timer = reader.u8()?;
+    let mut input = [false; 16];
+    for key in input.iter_mut() {
+        *key = reader.bool()?;
+    }
+    let key_wait = match (reader.bool()?, reader.u8()?, reader.bool()?, reader.u8()?) {
+        (false, _, _, _) => None,
+        (true, x, false, _) => Some((x & 0xF, None)),
+        (true, x, true, key) => Some((x & 0xF, Some(key & 0xF)))
+    };
+
+    let resolution = if reader.bool()? { Resolution::High } else { Resolution::Low };
+    let mut display = Framebuffer::new(resolution);
+    for plane in [PLANE_1, PLANE_2].iter() {
+        for y in 0..display.height() {
+            let row = reader.bytes(display.width() / 8)?;
+            for x in 0..display.width() {
+                if row[x / 8] & (0x80 >> (x % 8)) != 0 {
+                    display.set_color(x, y, display.color(x, y) | plane);
+                }
+            }
+        }
+    }
+
+    let flags = reader.u8()?;
+    let quirks = Quirks {
+        load_store_increment_i: flags & 1 != 0,
+        jump_uses_vx: flags & (1 << 1) != 0,
+        logic_resets_vf: flags & (1 << 2) != 0,
+        display_wait: flags & (1 << 3) != 0,
+        draw_mode: if flags & (1 << 4) != 0 { DrawMode::Wrap } else { DrawMode::Clip },
+        shift_mode: if flags & (1 << 5) != 0 { ShiftMode::InPlace } else { ShiftMode::FromVy },
+        key_wait_mode: if flags & (1 << 6) != 0 { KeyWaitMode::Press } else { KeyWaitMode::Release },
+        memory_size,
+        rpl_flag_count: if flags & (1 << 7) != 0 { XO_FLAG_COUNT } else { SCHIP_FLAG_COUNT }
+    };
+    let i = reader.u16()?;
+    let mut rpl_flags = [0; 16];
+    rpl_flags.copy_from_slice(reader.bytes(16)?);
+    let planes = reader.u8()? & (PLANE_1 | PLANE_2);
+    let mut audio_pattern = [0; 16];
+    audio_pattern.copy_from_slice(reader.bytes(16)?);
+    let pitch = reader.u8()?;
+    let rng_seed = reader.u64()?;
+    let rng_word_pos = u128::from(reader.u64()?) | u128::from(reader.u64()?) << 64;
+    let state = reader.bool()?;
+
+    if !reader.data.is_empty() {
+        return None;
+    }
+    Some(Snapshot {
+        memory,
+        pc,
+        regs,
+        stack,
+        stack_ptr,
+        delay_timer,
+        sound_timer,
+        input,
+        key_wait,
+        display,
+        quirks,
+        i,
+        rpl_flags,
+        planes,
+        audio_pattern,
+        pitch,
+        rng_seed,
+        rng_word_pos,
+        state
+    })
+}
+
+/// Adler-32 checksum
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for byte in data {
+        a = (a + *byte as u32) % MOD;
+        b = (b + a) % MOD;
+    }
+    (b << 16) | a
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::vm::VmBuilder;
+
+    /// LD I, 0x050; DRW V0, V0, 5; RND V1, 0xFF; CALL 0x20A; JP 0x200; RET
+    const ROM: [u8; 12] = [0xA0, 0x50, 0xD0, 0x05, 0xC1, 0xFF, 0x22, 0x0A, 0x12, 0x00, 0x00, 0xEE];
+
+    #[test]
+    fn test_save_load_state() {
+        let mut vm = VM::try_from(&ROM[..]).unwrap();
+        for _ in 0..4 {
+            vm.execute_next().unwrap();
+        }
+        vm.press_key(0xA);
+        vm.tick_timers();
+
+        let mut saved = vec![];
+        vm.save_state(&mut saved).unwrap();
+        let expected = vm.snapshot();
+
+        let mut restored = VM::try_from(&[][..]).unwrap();
+        restored.load_state(&mut &saved[..]).unwrap();
+        assert_eq!(restored.snapshot(), expected);
+        assert_eq!(restored.stack(), &[0x208]);
+        assert!(restored.is_key_pressed(0xA));
+
+        // Both VMs produce the same random numbers
+        for _ in 0..4 {
+            vm.execute_next().unwrap();
+            restored.execute_next().unwrap();
+        }
+        assert_eq!(vm.registers(), restored.registers());
+    }
+
+    #[test]
+    fn test_snapshot_keeps_rng() {
+        // RND V0, 0xFF; JP 0x200
+        let rom = [0xC0, 0xFF, 0x12, 0x00];
+        let mut vm1 = VmBuilder::new().seed(7).build(&rom).unwrap();
+        let mut vm2 = VmBuilder::new().seed(7).build(&rom).unwrap();
+
+        for _ in 0..20 {
+            vm1.snapshot();
+            vm1.execute_next().unwrap();
+            vm2.execute_next().unwrap();
+            assert_eq!(vm1.registers(), vm2.registers());
+        }
+    }
+
+    #[test]
+    fn test_bad_state() {
+        let mut vm = VM::try_from(&ROM[..]).unwrap();
+        let mut saved = vec![];
+        vm.save_state(&mut saved).unwrap();
+
+        let mut corrupted = saved.clone();
+        corrupted[0x210] ^= 0xFF;
+        match vm.load_state(&mut &corrupted[..]) {
+            Err(VmError::BadSaveState { reason: "checksum mismatch" }) => (),
+            res => panic!("Unexpected result {:?}", res)
+        }
+
+        let mut version = saved.clone();
+        version[MAGIC.len()] = VERSION + 1;
+        assert!(vm.load_state(&mut &version[..]).is_err());
+        assert!(vm.load_state(&mut &saved[..10]).is_err());
+        assert!(vm.load_state(&mut &b"not a save state"[..]).is_err());
+    }
+
+    #[test]
+    fn test_memory_size_mismatch() {
+        let xo_vm = VmBuilder::new().quirks(Quirks::xochip()).build(&ROM).unwrap();
+        let mut vm = VM::try_from(&ROM[..]).unwrap();
+        let expected = vm.snapshot();
+
+        match vm.restore(&xo_vm.snapshot()) {
+            Err(VmError::BadSaveState { reason: "memory size mismatch" }) => (),
+            res => panic!("Unexpected result {:?}", res)
+        }
+        let mut saved = vec![];
+        xo_vm.save_state(&mut saved).unwrap();
+        match vm.load_state(&mut &saved[..]) {
+            Err(VmError::BadSaveState { reason: "memory size mismatch" }) => (),
+            res => panic!("Unexpected result {:?}", res)
+        }
+        assert_eq!(vm.snapshot(), expected);
+    }
+}
@@ -1,27 +1,29 @@
-use rand::{ self, Rng };
+use rand::Rng;
 
-use crate::vm::{ VM };
+use crate::vm::{ VM, VmError, DrawMode, ShiftMode, KeyWait, Resolution, PLANE_1, PLANE_2 };
+use crate::vm::font::{ FONT_ADDR, GLYPH_SIZE, BIG_FONT_ADDR, BIG_GLYPH_SIZE };
 
 pub trait VmInstructions {
     fn clear(&mut self);
-    fn return_subroutine(&mut self);
+    fn return_subroutine(&mut self) -> Result<(), VmError>;
     fn goto(&mut self, addr: u16);
-    fn call_subroutine(&mut self, addr: u16);
+    fn call_subroutine(&mut self, addr: u16) -> Result<(), VmError>;
     fn skip_equal(&mut self, v1: u8, v2: u8);
     fn skip_not_equal(&mut self, v1: u8, v2: u8);
     fn load(&mut self, idx: u8, value: u8);
     fn add(&mut self, idx: u8, value: u8);
+    fn add_reg(&mut self, x: u8, y: u8);
     fn sub(&mut self, x: u8, y: u8);
     fn revsub(&mut self, x: u8, y: u8);
     fn or(&mut self, x: u8, y: u8);
     fn and(&mut self, x: u8, y: u8);
     fn xor(&mut self, x: u8, y: u8);
-    fn shift_right(&mut self, x: u8);
-    fn shift_left(&mut self, x: u8);
+    fn shift_right(&mut self, x: u8, y: u8);
+    fn shift_left(&mut self, x: u8, y: u8);
     fn store_address(&mut self, addr: u16);
     fn jump(&mut self, addr: u16);
     fn rand(&mut self, x: u8, value: u8);
-    fn draw(&mut self, x: u8, y: u8, nibble: u8);
+    fn draw(&mut self, x: u8, y: u8, nibble: u8) -> Result<(), VmError>;
     fn skip_key_pressed(&mut self, x: u8);
     fn skip_not_key_pressed(&mut self, x: u8);
     fn set_delay_timer(&mut self, x: u8);
@@ -30,45 +32,60 @@ pub trait VmInstructions {
     fn store_delay_timer(&mut self, x: u8);
     fn increment_addr_reg(&mut self, x: u8);
     fn store_sprite_addr(&mut self, x: u8);
-    fn bcd(&mut self, x: u8);
-    fn register_dump(&mut self, x: u8);
-    fn register_load(&mut self, x: u8);
+    fn bcd(&mut self, x: u8) -> Result<(), VmError>;
+    fn register_dump(&mut self, x: u8) -> Result<(), VmError>;
+    fn register_load(&mut self, x: u8) -> Result<(), VmError>;
+    fn scroll(&mut self, dx: isize, dy: isize);
+    fn exit(&mut self);
+    fn set_resolution(&mut self, resolution: Resolution);
+    fn store_big_sprite_addr(&mut self, x: u8);
+    fn store_flags(&mut self, x: u8) -> Result<(), VmError>;
+    fn load_flags(&mut self, x: u8) -> Result<(), VmError>;
+    fn save_range(&mut self, x: u8, y: u8) -> Result<(), VmError>;
+    fn load_range(&mut self, x: u8, y: u8) -> Result<(), VmError>;
+    fn long_store_address(&mut self, addr: u16);
+    fn select_planes(&mut self, n: u8);
+    fn load_audio_pattern(&mut self) -> Result<(), VmError>;
+    fn set_pitch(&mut self, x: u8);
 }
 
 impl VmInstructions for VM {
     fn clear(&mut self) {
-        self.display = [[false; 64]; 32]
+        self.display.clear_planes(self.planes);
     }
 
-    fn return_subroutine(&mut self) {
-        self.pc = self.stack[self.stack_ptr];
-
+    fn return_subroutine(&mut self) -> Result<(), VmError> {
         if self.stack_ptr == 0 {
-            self.state = false;
-        } else {
-            self.stack_ptr -= 1;
+            return Err(VmError::StackUnderflow { pc: self.current_addr() });
         }
+        self.stack_ptr -= 1;
+        self.pc = self.stack[self.stack_ptr];
+        Ok(())
     }
 
     fn goto(&mut self, addr: u16) {
         self.pc = addr as usize;
     }
 
-    fn call_subroutine(&mut self, addr: u16) {
-        self.stack_ptr += 1;
+    fn call_subroutine(&mut self, addr: u16) -> Result<(), VmError> {
+        if self.stack_ptr >= self.stack.len() {
+            return Err(VmError::StackOverflow { pc: self.current_addr() });
+        }
         self.stack[self.stack_ptr] = self.pc;
+        self.stack_ptr += 1;
         self.pc = addr as usize;
+        Ok(())
     }
 
     fn skip_equal(&mut self, v1: u8, v2: u8) {
         if v1 == v2 {
-            self.pc += 2;
+            self.skip_next();
         }
     }
 
     fn skip_not_equal(&mut self, v1: u8, v2: u8) {
         if v1 != v2 {
-            self.pc += 2;
+            self.skip_next();
         }
     }
 
@@ -77,58 +94,72 @@ impl VmInstructions for VM {
     }
 
     fn add(&mut self, idx: u8, value: u8) {
-        self.regs[idx as usize] += value;
+        let ix = idx as usize;
+        self.regs[ix] = self.regs[ix].wrapping_add(value);
+    }
+
+    fn add_reg(&mut self, x: u8, y: u8) {
+        let ix = x as usize;
+        let last = self.regs.len() - 1;
+        let (result, carry) = self.regs[ix].overflowing_add(self.regs[y as usize]);
+
+        // VF is written last so that the flag wins when X is F, same in sub and revsub
+        self.regs[ix] = result;
+        self.regs[last] = carry as u8;
     }
 
     fn sub(&mut self, x: u8, y: u8) {
         let ix = x as usize;
-        let iy = y as usize;
         let last = self.regs.len() - 1;
+        let (result, borrow) = self.regs[ix].overflowing_sub(self.regs[y as usize]);
 
-        self.regs[ix] -= self.regs[iy];
-        self.regs[last] = if self.regs[ix] > self.regs[iy] { 1 } else  { 0 }
+        self.regs[ix] = result;
+        self.regs[last] = !borrow as u8;
     }
 
     fn revsub(&mut self, x: u8, y: u8) {
         let ix = x as usize;
-        let iy = y as usize;
         let last = self.regs.len() - 1;
+        let (result, borrow) = self.regs[y as usize].overflowing_sub(self.regs[ix]);
 
-        self.regs[ix] = self.regs[iy] - self.regs[ix];
-        self.regs[last] = if self.regs[ix] > self.regs[iy] { 1 } else  { 0 }
+        self.regs[ix] = result;
+        self.regs[last] = !borrow as u8;
     }
 
     fn or(&mut self, x: u8, y: u8) {
         let idx = x as usize;
-        self.regs[idx] = self.regs[idx] | self.regs[y as usize];
+        self.regs[idx] |= self.regs[y as usize];
+        self.logic_reset_vf();
     }
 
     fn and(&mut self, x: u8, y: u8) {
         let idx = x as usize;
-        self.regs[idx] = self.regs[idx] & self.regs[y as usize];
+        self.regs[idx] &= self.regs[y as usize];
+        self.logic_reset_vf();
     }
 
     fn xor(&mut self, x: u8, y: u8) {
         let idx = x as usize;
-        self.regs[idx] = self.regs[idx] ^ self.regs[y as usize];
+        self.regs[idx] ^= self.regs[y as usize];
+        self.logic_reset_vf();
     }
 
-    fn shift_right(&mut self, x: u8) {
-        let ix = x as usize;
+    fn shift_right(&mut self, x: u8, y: u8) {
         let last = self.regs.len() - 1;
+        let value = self.shift_source(x, y);
 
-        // Set carry to lsb of Vx
-        self.regs[last] = self.regs[ix] & 1;
-        self.regs[ix] >>= 1;
+        // Set carry to lsb of the shifted value
+        self.regs[x as usize] = value >> 1;
+        self.regs[last] = value & 1;
     }
 
-    fn shift_left(&mut self, x: u8) {
-        let ix = x as usize;
+    fn shift_left(&mut self, x: u8, y: u8) {
         let last = self.regs.len() - 1;
+        let value = self.shift_source(x, y);
 
-        // Set carry to lsb of Vx
-        self.regs[last] = self.regs[ix] & 1;
-        self.regs[ix] <<= 1;
+        // Set carry to msb of the shifted value
+        self.regs[x as usize] = value << 1;
+        self.regs[last] = value >> 7;
     }
 
     fn store_address(&mut self, addr: u16) {
@@ -136,31 +167,74 @@ impl VmInstructions for VM {
     }
 
     fn jump(&mut self, addr: u16) {
-        self.pc = self.regs[0] as usize + addr as usize;
+        let reg = if self.quirks.jump_uses_vx { (addr >> 8) & 0xF } else { 0 };
+        self.pc = self.regs[reg as usize] as usize + addr as usize;
     }
 
     fn rand(&mut self, x: u8, value: u8) {
-        let mut rng = rand::thread_rng();
-        self.regs[x as usize] = rng.gen_range(0, 255) & value;
+        self.regs[x as usize] = self.rng.gen::<u8>() & value;
     }
 
-    fn draw(&mut self, x: u8, y: u8, nibble: u8) {
-        // todo
+    fn draw(&mut self, x: u8, y: u8, nibble: u8) -> Result<(), VmError> {
+        // DXY0 draws a 16x16 sprite made of two bytes per row
+        let (cols, rows) = if nibble == 0 { (16, 16) } else { (8, nibble as usize) };
+        let row_size = cols / 8;
+
+        // Starting coordinates always wrap, only the sprite itself may be clipped
+        let width = self.display.width();
+        let height = self.display.height();
+        let vx = self.regs[x as usize] as usize % width;
+        let vy = self.regs[y as usize] as usize % height;
+        let wrap = self.quirks.draw_mode == DrawMode::Wrap;
+        let mut collision = false;
+
+        // With both planes selected, the sprite of the second plane follows the first one
+        let planes = self.planes;
+        let mut addr = self.i as usize;
+        let plane_count = [PLANE_1, PLANE_2].iter().filter(|plane| planes & **plane != 0).count();
+        self.check_memory_range(addr, rows * row_size * plane_count)?;
+        for plane in [PLANE_1, PLANE_2].iter().filter(|plane| planes & **plane != 0) {
+            for row in 0..rows {
+                let py = vy + row;
+                if py >= height && !wrap {
+                    break;
+                }
+                let mut sprite = 0u16;
+                for byte in 0..row_size {
+                    sprite = sprite << 8 | self.read_memory(addr + row * row_size + byte)? as u16;
+                }
+                let sprite = sprite << (16 - cols);
+
+                for col in 0..cols {
+                    let px = vx + col;
+                    if px >= width && !wrap {
+                        break;
+                    }
+                    if sprite & (0x8000 >> col) == 0 {
+                        continue;
+                    }
+                    collision |= self.display.xor_planes(px % width, py % height, *plane);
+                }
+            }
+            addr += rows * row_size;
+        }
+        self.regs[0xF] = collision as u8;
+        Ok(())
     }
 
     fn skip_key_pressed(&mut self, x: u8) {
-        let idx = self.regs[x as usize] as usize;
+        let idx = (self.regs[x as usize] & 0xF) as usize;
 
         if self.input[idx] {
-            self.pc += 2;
+            self.skip_next();
         }
     }
 
     fn skip_not_key_pressed(&mut self, x: u8) {
-        let idx = self.regs[x as usize] as usize;
+        let idx = (self.regs[x as usize] & 0xF) as usize;
 
         if !self.input[idx] {
-            self.pc += 2;
+            self.skip_next();
         }
     }
 
@@ -169,7 +243,7 @@ impl VmInstructions for VM {
     }
 
     fn wait_key_pressed(&mut self, x: u8) {
-        // todo
+        self.key_wait = Some(KeyWait { x, pressed: None });
     }
 
     fn set_sound_timer(&mut self, x: u8) {
@@ -181,37 +255,781 @@ impl VmInstructions for VM {
     }
 
     fn increment_addr_reg(&mut self, x: u8) {
-        self.i += self.regs[x as usize] as u16;
+        self.i = self.i.wrapping_add(self.regs[x as usize] as u16);
     }
 
     fn store_sprite_addr(&mut self, x: u8) {
-        // todo
+        let digit = (self.regs[x as usize] & 0xF) as usize;
+        self.i = (FONT_ADDR + digit * GLYPH_SIZE) as u16;
     }
 
-    fn bcd(&mut self, x: u8) {
+    fn bcd(&mut self, x: u8) -> Result<(), VmError> {
         let vx = self.regs[x as usize];
-        let x_bcd = ((vx / 100) % 10, (vx / 10) % 10, vx % 10);
         let idx = self.i as usize;
 
-        self.memory[idx] = x_bcd.0;
-        self.memory[idx + 1] = x_bcd.1;
-        self.memory[idx + 2] = x_bcd.2;
+        self.check_memory_range(idx, 3)?;
+        self.write_memory(idx, vx / 100)?;
+        self.write_memory(idx + 1, (vx / 10) % 10)?;
+        self.write_memory(idx + 2, vx % 10)
+    }
+
+    fn register_dump(&mut self, x: u8) -> Result<(), VmError> {
+        let idx = self.i as usize;
+        self.check_memory_range(idx, x as usize + 1)?;
+        for j in 0..=(x as usize) {
+            self.write_memory(idx + j, self.regs[j])?;
+        }
+        self.load_store_increment_i(x);
+        Ok(())
+    }
+
+    fn register_load(&mut self, x: u8) -> Result<(), VmError> {
+        let idx = self.i as usize;
+        self.check_memory_range(idx, x as usize + 1)?;
+        for j in 0..=(x as usize) {
+            self.regs[j] = self.read_memory(idx + j)?;
+        }
+        self.load_store_increment_i(x);
+        Ok(())
+    }
+
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        self.display.scroll_planes(dx, dy, self.planes);
+    }
+
+    fn exit(&mut self) {
+        self.state = false;
+    }
+
+    fn set_resolution(&mut self, resolution: Resolution) {
+        self.display.set_resolution(resolution);
+    }
+
+    fn store_big_sprite_addr(&mut self, x: u8) {
+        let digit = (self.regs[x as usize] & 0xF) as usize;
+        self.i = (BIG_FONT_ADDR + digit * BIG_GLYPH_SIZE) as u16;
+    }
+
+    fn store_flags(&mut self, x: u8) -> Result<(), VmError> {
+        let len = self.flag_range(x)?;
+        self.rpl_flags[..len].copy_from_slice(&self.regs[..len]);
+        Ok(())
+    }
+
+    fn load_flags(&mut self, x: u8) -> Result<(), VmError> {
+        let len = self.flag_range(x)?;
+        self.regs[..len].copy_from_slice(&self.rpl_flags[..len]);
+        Ok(())
+    }
+
+    fn save_range(&mut self, x: u8, y: u8) -> Result<(), VmError> {
+        let idx = self.i as usize;
+        let (x, y) = (x as usize, y as usize);
+        self.check_memory_range(idx, x.abs_diff(y) + 1)?;
+
+        // VX is at I, the registers are stored in reverse order when X > Y
+        for reg in x.min(y)..=x.max(y) {
+            self.write_memory(idx + reg.abs_diff(x), self.regs[reg])?;
+        }
+        Ok(())
+    }
+
+    fn load_range(&mut self, x: u8, y: u8) -> Result<(), VmError> {
+        let idx = self.i as usize;
+        let (x, y) = (x as usize, y as usize);
+        self.check_memory_range(idx, x.abs_diff(y) + 1)?;
+        for reg in x.min(y)..=x.max(y) {
+            self.regs[reg] = self.read_memory(idx + reg.abs_diff(x))?;
+        }
+        Ok(())
+    }
+
+    fn long_store_address(&mut self, addr: u16) {
+        self.i = addr;
+    }
+
+    fn select_planes(&mut self, n: u8) {
+        self.planes = n & (PLANE_1 | PLANE_2);
+    }
+
+    fn load_audio_pattern(&mut self) -> Result<(), VmError> {
+        let idx = self.i as usize;
+        self.check_memory_range(idx, self.audio_pattern.len())?;
+        for j in 0..self.audio_pattern.len() {
+            self.audio_pattern[j] = self.read_memory(idx + j)?;
+        }
+        Ok(())
+    }
+
+    fn set_pitch(&mut self, x: u8) {
+        self.pitch = self.regs[x as usize];
+    }
+}
+
+
+impl VM {
+    fn logic_reset_vf(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.regs[0xF] = 0;
+        }
+    }
+
+    /// Flags saved or loaded by FX75 and FX85, SCHIP only has 8 of them
+    fn flag_range(&self, x: u8) -> Result<usize, VmError> {
+        let len = (x & 0xF) as usize + 1;
+        if len > self.rpl_flag_count() {
+            return Err(VmError::FlagOutOfRange { pc: self.current_addr(), x, count: self.rpl_flag_count() });
+        }
+        Ok(len)
     }
 
-    fn register_dump(&mut self, x: u8) {
-        let mut idx = self.i as usize;
-        for j in 0..(x as usize) {
-            self.memory[idx] = self.regs[j];
-            idx += 1;
+    fn load_store_increment_i(&mut self, x: u8) {
+        if self.quirks.load_store_increment_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
         }
     }
 
-    fn register_load(&mut self, x: u8) {
-        let mut idx = self.i as usize;
-        for j in 0..(x as usize) {
-            self.regs[j] = self.memory[idx];
-            idx += 1;
+    /// Skip the next instruction, F000 NNNN being 4 bytes long
+    fn skip_next(&mut self) {
+        self.pc += match self.memory.get(self.pc..self.pc + 2) {
+            Some([0xF0, 0x00]) => 4,
+            _ => 2
+        };
+    }
+
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        match self.quirks.shift_mode {
+            ShiftMode::FromVy => self.regs[y as usize],
+            ShiftMode::InPlace => self.regs[x as usize]
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::vm::{ VmBuilder, VmError, Quirks, KeyWaitMode, KeyEvent, XO_MEMORY_SIZE };
+    use crate::vm::font::DEFAULT_FONT;
+
+    /// Build a VM with the given sprite loaded at I
+    fn vm_with_sprite(sprite: &[u8]) -> VM {
+        let mut vm = VM::try_from(sprite).unwrap();
+        vm.i = 0x200;
+        vm
+    }
+
+    fn lit_pixels(vm: &VM) -> Vec<(usize, usize)> {
+        let mut pixels = vec![];
+        for (y, row) in vm.display.rows().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                if *pixel != 0 {
+                    pixels.push((x, y));
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn test_draw_sprite() {
+        let mut vm = vm_with_sprite(&[0b1010_0000, 0b0101_0000]);
+        vm.regs[0] = 3;
+        vm.regs[1] = 4;
+        vm.draw(0, 1, 2).unwrap();
+
+        assert_eq!(lit_pixels(&vm), vec![(3, 4), (5, 4), (4, 5), (6, 5)]);
+        assert_eq!(vm.regs[0xF], 0);
+    }
+
+    #[test]
+    fn test_draw_collision() {
+        let mut vm = vm_with_sprite(&[0xFF]);
+        vm.draw(0, 0, 1).unwrap();
+        assert_eq!(vm.regs[0xF], 0);
+
+        // Drawing the same sprite again erases it and reports a collision
+        vm.draw(0, 0, 1).unwrap();
+        assert_eq!(vm.regs[0xF], 1);
+        assert!(lit_pixels(&vm).is_empty());
+
+        // VF is reset when nothing collides
+        vm.draw(0, 0, 1).unwrap();
+        assert_eq!(vm.regs[0xF], 0);
+    }
+
+    #[test]
+    fn test_draw_partial_collision() {
+        let mut vm = vm_with_sprite(&[0b1000_0000, 0b1100_0000]);
+        vm.draw(0, 0, 1).unwrap();
+        vm.i = 0x201;
+        vm.draw(0, 0, 1).unwrap();
+
+        assert_eq!(vm.regs[0xF], 1);
+        assert_eq!(lit_pixels(&vm), vec![(1, 0)]);
+    }
+
+    #[test]
+    fn test_draw_collision_in_vf() {
+        let mut vm = vm_with_sprite(&[0xFF]);
+        vm.regs[0xF] = 8;
+        vm.draw(0xF, 0xF, 1).unwrap();
+        vm.draw(0, 0, 1).unwrap();
+
+        assert_eq!(vm.regs[0xF], 0);
+        assert_eq!(lit_pixels(&vm).len(), 16);
+    }
+
+    #[test]
+    fn test_draw_clip_edges() {
+        let mut vm = vm_with_sprite(&[0xFF, 0xFF]);
+        vm.regs[0] = 60;
+        vm.regs[1] = 31;
+        vm.draw(0, 1, 2).unwrap();
+
+        assert_eq!(lit_pixels(&vm), vec![(60, 31), (61, 31), (62, 31), (63, 31)]);
+    }
+
+    #[test]
+    fn test_draw_wrap_edges() {
+        let mut vm = vm_with_sprite(&[0xFF, 0xFF]);
+        vm.set_quirks(Quirks { draw_mode: DrawMode::Wrap, ..Quirks::default() });
+        vm.regs[0] = 62;
+        vm.regs[1] = 31;
+        vm.draw(0, 1, 2).unwrap();
+
+        let pixels = lit_pixels(&vm);
+        assert_eq!(pixels.len(), 16);
+        assert!(pixels.contains(&(62, 31)));
+        assert!(pixels.contains(&(5, 31)));
+        assert!(pixels.contains(&(63, 0)));
+        assert!(pixels.contains(&(0, 0)));
+    }
+
+    #[test]
+    fn test_draw_start_coordinates_wrap() {
+        let mut vm = vm_with_sprite(&[0b1000_0000]);
+        vm.regs[0] = 64 + 2;
+        vm.regs[1] = 32 + 3;
+        vm.draw(0, 1, 1).unwrap();
+
+        assert_eq!(lit_pixels(&vm), vec![(2, 3)]);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut vm = vm_with_sprite(&[0xFF]);
+        vm.draw(0, 0, 1).unwrap();
+        vm.clear();
+
+        assert!(lit_pixels(&vm).is_empty());
+    }
+
+    #[test]
+    fn test_store_sprite_addr() {
+        let mut vm = vm_with_sprite(&[]);
+        vm.regs[3] = 0xA;
+        vm.store_sprite_addr(3);
+
+        let glyph = &vm.memory[vm.i as usize..vm.i as usize + GLYPH_SIZE];
+        assert_eq!(glyph, &[0xF0, 0x90, 0xF0, 0x90, 0x90]);
+    }
+
+    #[test]
+    fn test_custom_font() {
+        let mut font = DEFAULT_FONT;
+        font[GLYPH_SIZE..GLYPH_SIZE * 2].copy_from_slice(&[0xFF; GLYPH_SIZE]);
+        let mut vm = VmBuilder::new().font(&font).build(&[]).unwrap();
+        vm.regs[0] = 1;
+        vm.store_sprite_addr(0);
+        vm.draw(1, 1, 5).unwrap();
+
+        assert_eq!(lit_pixels(&vm).len(), 8 * GLYPH_SIZE);
+        assert!(VmBuilder::new().font(&font[1..]).build(&[]).is_err());
+    }
+
+    #[test]
+    fn test_wait_key_released() {
+        let mut vm = VM::try_from(&[0xF3, 0x0A, 0x60, 0x01][..]).unwrap();
+        vm.execute_next().unwrap();
+        vm.execute_next().unwrap();
+        assert!(vm.is_awaiting_key());
+        assert_eq!(vm.pc, 0x202);
+
+        vm.press_key(0x5);
+        vm.execute_next().unwrap();
+        assert!(vm.is_awaiting_key());
+
+        vm.release_key(0x5);
+        assert!(!vm.is_awaiting_key());
+        assert_eq!(vm.regs[3], 0x5);
+
+        vm.execute_next().unwrap();
+        assert_eq!(vm.regs[0], 1);
+    }
+
+    #[test]
+    fn test_wait_key_pressed() {
+        let rom = [0xF3, 0x0A];
+        let quirks = Quirks { key_wait_mode: KeyWaitMode::Press, ..Quirks::default() };
+        let mut vm = VmBuilder::new().quirks(quirks).build(&rom).unwrap();
+        vm.execute_next().unwrap();
+
+        // Releasing a key held before the wait does not resume execution
+        vm.release_key(0x2);
+        assert!(vm.is_awaiting_key());
+
+        vm.press_key(0xB);
+        assert!(!vm.is_awaiting_key());
+        assert_eq!(vm.regs[3], 0xB);
+    }
+
+    #[test]
+    fn test_key_event_queue() {
+        // SKP V0 twice, V0 = 7
+        let mut vm = VM::try_from(&[0xE0, 0x9E, 0xE0, 0x9E][..]).unwrap();
+        vm.regs[0] = 7;
+        vm.push_key_event(KeyEvent::Pressed(7));
+        vm.push_key_event(KeyEvent::Released(7));
+
+        vm.execute_next().unwrap();
+        assert_eq!(vm.pc, 0x204);
+        assert!(vm.is_key_pressed(7));
+
+        vm.pc = 0x200;
+        vm.execute_next().unwrap();
+        assert_eq!(vm.pc, 0x202);
+        assert!(!vm.is_key_pressed(7));
+    }
+
+    #[test]
+    fn test_set_keys() {
+        let mut vm = vm_with_sprite(&[]);
+        let mut keys = [false; 16];
+        keys[0xE] = true;
+        vm.set_keys(&keys);
+        vm.regs[1] = 0xE;
+        vm.skip_not_key_pressed(1);
+
+        assert_eq!(vm.pc, 0x200);
+        assert!(vm.is_key_pressed(0xE));
+    }
+
+    #[test]
+    fn test_timers_tick_per_frame() {
+        // LD V0, 3; LD DT, V0; LD ST, V0; LD V1, DT; JP 0x206
+        let rom = [0x60, 0x03, 0xF0, 0x15, 0xF0, 0x18, 0xF1, 0x07, 0x12, 0x06];
+        let mut vm = VM::try_from(&rom[..]).unwrap();
+        vm.run_frame(3).unwrap();
+        assert_eq!(vm.delay_timer, 2);
+        assert!(vm.is_sound_active());
+
+        // Timers only depend on the number of frames, not on the instructions
+        vm.run_frame(50).unwrap();
+        vm.run_frame(1).unwrap();
+        assert_eq!(vm.delay_timer, 0);
+        assert_eq!(vm.sound_timer, 0);
+        assert!(!vm.is_sound_active());
+
+        vm.tick_timers();
+        assert_eq!(vm.delay_timer, 0);
+    }
+
+    #[test]
+    fn test_vm_errors() {
+        let mut vm = VM::try_from(&[0x60, 0x01, 0xFF, 0xFF][..]).unwrap();
+        vm.execute_next().unwrap();
+
+        match vm.execute_next() {
+            Err(VmError::UnknownOpcode { addr: 0x202, opcode: (0xFF, 0xFF) }) => (),
+            res => panic!("Unexpected result {:?}", res)
+        }
+        match VM::try_from(&[0; 4096][..]) {
+            Err(VmError::RomTooLarge { size: 4096, max: 3584 }) => (),
+            _ => panic!("ROM should not fit in memory")
+        }
+    }
+
+    #[test]
+    fn test_stack_errors() {
+        // CALL 0x200
+        let mut vm = VM::try_from(&[0x22, 0x00][..]).unwrap();
+        for _ in 0..32 {
+            vm.execute_next().unwrap();
+        }
+        match vm.execute_next() {
+            Err(VmError::StackOverflow { pc: 0x200 }) => (),
+            res => panic!("Unexpected result {:?}", res)
+        }
+
+        // RET
+        let mut vm = VM::try_from(&[0x00, 0xEE][..]).unwrap();
+        match vm.execute_next() {
+            Err(VmError::StackUnderflow { pc: 0x200 }) => (),
+            res => panic!("Unexpected result {:?}", res)
+        }
+        assert_eq!(vm.pc, 0x200);
+    }
+
+    #[test]
+    fn test_subroutine() {
+        // CALL 0x206; LD V1, 2; JP 0x204; LD V0, 1; RET
+        let rom = [0x22, 0x06, 0x61, 0x02, 0x12, 0x04, 0x60, 0x01, 0x00, 0xEE];
+        let mut vm = VM::try_from(&rom[..]).unwrap();
+        for _ in 0..5 {
+            vm.execute_next().unwrap();
+        }
+
+        assert_eq!((vm.regs[0], vm.regs[1]), (1, 2));
+        assert_eq!(vm.stack_ptr, 0);
+        assert_eq!(vm.pc, 0x204);
+    }
+
+    #[test]
+    fn test_memory_errors() {
+        // LD I, 0xFFE; LD B, V0
+        let mut vm = VM::try_from(&[0xAF, 0xFE, 0xF0, 0x33][..]).unwrap();
+        vm.execute_next().unwrap();
+        match vm.execute_next() {
+            Err(VmError::MemoryOutOfBounds { pc: 0x202, addr: 0x1000 }) => (),
+            res => panic!("Unexpected result {:?}", res)
+        }
+
+        // LD I, 0xFFF; LD V1, I; DRW V0, V0, 2
+        let mut vm = VM::try_from(&[0xAF, 0xFF, 0xF1, 0x65, 0xD0, 0x02][..]).unwrap();
+        vm.execute_next().unwrap();
+        assert!(vm.execute_next().is_err());
+        vm.pc = 0x204;
+        vm.memory[0xFFF] = 0xFF;
+        vm.regs[0xF] = 0xAA;
+        match vm.execute_next() {
+            Err(VmError::MemoryOutOfBounds { pc: 0x204, addr: 0x1000 }) => (),
+            res => panic!("Unexpected result {:?}", res)
+        }
+        assert_eq!(vm.display.dirty(), None);
+        assert_eq!(vm.regs[0xF], 0xAA);
+
+        // LD I, 0xFFE; LD [I], V3; LD V3, [I]: nothing is written before the error
+        let mut vm = VM::try_from(&[0xAF, 0xFE, 0xF3, 0x55, 0xF3, 0x65][..]).unwrap();
+        vm.regs[..4].copy_from_slice(&[1, 2, 3, 4]);
+        vm.execute_next().unwrap();
+        assert!(vm.execute_next().is_err());
+        assert_eq!(&vm.memory[0xFFE..], &[0, 0]);
+        vm.pc = 0x204;
+        assert!(vm.execute_next().is_err());
+        assert_eq!(&vm.regs[..4], &[1, 2, 3, 4]);
+
+        // LD I, 0xFF8; AUDIO
+        let mut vm = VM::try_from(&[0xAF, 0xF8, 0xF0, 0x02][..]).unwrap();
+        vm.memory[0xFF8..].copy_from_slice(&[1; 8]);
+        vm.execute_next().unwrap();
+        assert!(vm.execute_next().is_err());
+        assert_eq!(vm.audio_pattern, [0; 16]);
+
+        // JP 0xFFF
+        let mut vm = VM::try_from(&[0x1F, 0xFF][..]).unwrap();
+        vm.execute_next().unwrap();
+        match vm.execute_next() {
+            Err(VmError::PcOutOfRange { pc: 0xFFF }) => (),
+            res => panic!("Unexpected result {:?}", res)
+        }
+    }
+
+    /// Execute a single ALU opcode with VX and VY set, VF starts at 0xAA
+    /// unless it is one of the operands. Returns (VX, VF)
+    fn run_alu(opcode: u16, vx: u8, vy: u8) -> (u8, u8) {
+        let rom = [(opcode >> 8) as u8, opcode as u8];
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+        let mut vm = VM::try_from(&rom[..]).unwrap();
+
+        vm.regs[0xF] = 0xAA;
+        if opcode & 0xF000 == 0x8000 {
+            vm.regs[y] = vy;
+        }
+        vm.regs[x] = vx;
+        vm.execute_next().unwrap();
+        (vm.regs[x], vm.regs[0xF])
+    }
+
+    #[test]
+    fn test_alu() {
+        // (opcode, VX, VY, expected VX, expected VF)
+        let cases: &[(u16, u8, u8, u8, u8)] = &[
+            // 7XNN, no flag
+            (0x7105, 0x10, 0x00, 0x15, 0xAA),
+            (0x71FF, 0x02, 0x00, 0x01, 0xAA),
+            (0x7F01, 0xFF, 0xFF, 0x00, 0x00),
+            // 8XY0
+            (0x8120, 0x00, 0x42, 0x42, 0xAA),
+            // 8XY1, 8XY2, 8XY3, VF reset
+            (0x8121, 0b1100, 0b1010, 0b1110, 0x00),
+            (0x8122, 0b1100, 0b1010, 0b1000, 0x00),
+            (0x8123, 0b1100, 0b1010, 0b0110, 0x00),
+            // 8XY4, VF = carry
+            (0x8124, 0x10, 0x20, 0x30, 0x00),
+            (0x8124, 0xFF, 0x01, 0x00, 0x01),
+            (0x8124, 0xFF, 0xFF, 0xFE, 0x01),
+            (0x8F24, 0xFF, 0x01, 0x01, 0x01),
+            (0x81F4, 0x01, 0xFF, 0x00, 0x01),
+            // 8XY5, VF = not borrow
+            (0x8125, 0x30, 0x10, 0x20, 0x01),
+            (0x8125, 0x10, 0x10, 0x00, 0x01),
+            (0x8125, 0x10, 0x30, 0xE0, 0x00),
+            (0x8F25, 0x10, 0x30, 0x00, 0x00),
+            (0x81F5, 0x10, 0x30, 0xE0, 0x00),
+            // 8XY7, VF = not borrow
+            (0x8127, 0x10, 0x30, 0x20, 0x01),
+            (0x8127, 0x10, 0x10, 0x00, 0x01),
+            (0x8127, 0x30, 0x10, 0xE0, 0x00),
+            (0x8F27, 0x10, 0x30, 0x01, 0x01),
+            (0x81F7, 0x30, 0x10, 0xE0, 0x00),
+            // 8XY6, VF = shifted out bit, VX = VY >> 1
+            (0x8126, 0x00, 0b0000_0101, 0b0000_0010, 0x01),
+            (0x8126, 0xFF, 0b0000_0100, 0b0000_0010, 0x00),
+            (0x8F06, 0x00, 0b0000_0011, 0x01, 0x01),
+            (0x81F6, 0x00, 0b0000_0011, 0x01, 0x01),
+            // 8XYE, VF = shifted out bit, VX = VY << 1
+            (0x812E, 0x00, 0b1000_0001, 0b0000_0010, 0x01),
+            (0x812E, 0xFF, 0b0100_0001, 0b1000_0010, 0x00),
+            (0x8F0E, 0x00, 0b1100_0000, 0x01, 0x01),
+            (0x81FE, 0x00, 0b1100_0000, 0x80, 0x01),
+        ];
+
+        for &(opcode, vx, vy, expected_vx, expected_vf) in cases {
+            assert_eq!(
+                run_alu(opcode, vx, vy),
+                (expected_vx, expected_vf),
+                "opcode 0x{:04X} with VX = 0x{:02X}, VY = 0x{:02X}",
+                opcode,
+                vx,
+                vy
+            );
+        }
+    }
+
+    #[test]
+    fn test_shift_in_place() {
+        let quirks = Quirks { shift_mode: ShiftMode::InPlace, ..Quirks::default() };
+        let mut vm = VmBuilder::new().quirks(quirks).build(&[]).unwrap();
+        vm.regs[1] = 0b1000_0011;
+        vm.regs[2] = 0xFF;
+        vm.shift_left(1, 2);
+        assert_eq!((vm.regs[1], vm.regs[0xF]), (0b0000_0110, 1));
+
+        vm.shift_right(1, 2);
+        assert_eq!((vm.regs[1], vm.regs[0xF]), (0b0000_0011, 0));
+        assert_eq!(vm.regs[2], 0xFF);
+    }
+
+    #[test]
+    fn test_quirks_load_store() {
+        let mut vm = vm_with_sprite(&[]);
+        vm.regs[..3].copy_from_slice(&[1, 2, 3]);
+        vm.i = 0x300;
+        vm.register_dump(2).unwrap();
+        assert_eq!(&vm.memory[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!(vm.i, 0x303);
+
+        vm.set_quirks(Quirks::schip());
+        vm.i = 0x300;
+        vm.register_load(1).unwrap();
+        assert_eq!(vm.i, 0x300);
+    }
+
+    #[test]
+    fn test_quirks_jump() {
+        let mut vm = vm_with_sprite(&[]);
+        vm.regs[0] = 0x10;
+        vm.regs[3] = 0x20;
+        vm.jump(0x300);
+        assert_eq!(vm.pc, 0x310);
+
+        vm.set_quirks(Quirks::chip48());
+        vm.jump(0x300);
+        assert_eq!(vm.pc, 0x320);
+    }
+
+    #[test]
+    fn test_quirks_logic_vf() {
+        let mut vm = vm_with_sprite(&[]);
+        vm.set_quirks(Quirks::xochip());
+        vm.regs[0xF] = 1;
+        vm.or(0, 1);
+
+        assert_eq!(vm.regs[0xF], 1);
+    }
+
+    #[test]
+    fn test_quirks_display_wait() {
+        // DRW V0, V0, 1; JP 0x200
+        let rom = [0xD0, 0x01, 0x12, 0x00];
+        let mut vm = VM::try_from(&rom[..]).unwrap();
+        vm.run_frame(10).unwrap();
+        assert_eq!(vm.pc, 0x202);
+
+        vm.set_quirks(Quirks::schip());
+        vm.pc = 0x200;
+        vm.run_frame(2).unwrap();
+        assert_eq!(vm.pc, 0x200);
+    }
+
+    #[test]
+    fn test_quirks_presets() {
+        assert_eq!(Quirks::from_preset("VIP"), Some(Quirks::default()));
+        for name in crate::vm::quirks::PRESETS.iter() {
+            assert!(Quirks::from_preset(name).is_some());
+        }
+        assert_eq!(Quirks::from_preset("chip-9"), None);
+
+        let vm = VmBuilder::new().quirks(Quirks::xochip()).build(&[]).unwrap();
+        assert_eq!(vm.memory().len(), XO_MEMORY_SIZE);
+        let vm = VmBuilder::new().memory_size(0x2000).quirks(Quirks::xochip()).build(&[]).unwrap();
+        assert_eq!((vm.memory().len(), vm.quirks().memory_size), (0x2000, 0x2000));
+    }
+
+    #[test]
+    fn test_quirk_setters() {
+        let mut vm = VmBuilder::new()
+            .quirks(Quirks::vip())
+            .draw_mode(DrawMode::Wrap)
+            .key_wait_mode(KeyWaitMode::Press)
+            .shift_mode(ShiftMode::InPlace)
+            .build(&[])
+            .unwrap();
+        assert_eq!(vm.quirks().draw_mode, DrawMode::Wrap);
+        assert_eq!(vm.quirks().key_wait_mode, KeyWaitMode::Press);
+        assert_eq!(vm.quirks().shift_mode, ShiftMode::InPlace);
+        assert!(vm.quirks().display_wait);
+
+        vm.set_draw_mode(DrawMode::Clip);
+        assert_eq!(vm.quirks().draw_mode, DrawMode::Clip);
+    }
+
+    #[test]
+    fn test_rand_seed() {
+        let mut vm1 = VmBuilder::new().seed(42).build(&[]).unwrap();
+        let mut vm2 = VmBuilder::new().seed(42).build(&[]).unwrap();
+        for _ in 0..8 {
+            vm1.rand(0, 0xFF);
+            vm2.rand(0, 0xFF);
+            assert_eq!(vm1.regs[0], vm2.regs[0]);
+        }
+
+        vm1.rand(1, 0x0F);
+        assert_eq!(vm1.regs[1] & 0xF0, 0);
+    }
+
+    #[test]
+    fn test_schip_draw_16x16() {
+        let mut sprite = vec![0; 32];
+        sprite[0] = 0x80;
+        sprite[1] = 0x01;
+        sprite[31] = 0x01;
+        let mut vm = vm_with_sprite(&sprite);
+        vm.set_resolution(Resolution::High);
+        vm.regs[0] = 100;
+        vm.regs[1] = 10;
+        vm.draw(0, 1, 0).unwrap();
+
+        assert_eq!(lit_pixels(&vm), vec![(100, 10), (115, 10), (115, 25)]);
+        vm.draw(0, 1, 0).unwrap();
+        assert_eq!(vm.regs[0xF], 1);
+        assert!(lit_pixels(&vm).is_empty());
+    }
+
+    #[test]
+    fn test_schip_instructions() {
+        // HIGH; SCD 2; SCR; LD HF, V0; LD R, V2; LD V1, 0; LD V2, 0; LD V1, R; LOW; EXIT
+        let rom = [
+            0x00, 0xFF, 0x00, 0xC2, 0x00, 0xFB, 0xF0, 0x30, 0xF2, 0x75, 0x61, 0x00,
+            0x62, 0x00, 0xF1, 0x85, 0x00, 0xFE, 0x00, 0xFD
+        ];
+        let mut vm = VM::try_from(&rom[..]).unwrap();
+        vm.regs[..3].copy_from_slice(&[3, 7, 9]);
+
+        vm.execute_next().unwrap();
+        assert_eq!(vm.display.resolution(), Resolution::High);
+        vm.display.set(0, 0, true);
+        vm.execute_next().unwrap();
+        vm.execute_next().unwrap();
+        assert_eq!(lit_pixels(&vm), vec![(4, 2)]);
+
+        vm.execute_next().unwrap();
+        assert_eq!(vm.i as usize, BIG_FONT_ADDR + 3 * BIG_GLYPH_SIZE);
+        for _ in 0..4 {
+            vm.execute_next().unwrap();
+        }
+        assert_eq!(&vm.regs[..3], &[3, 7, 0]);
+        assert_eq!(&vm.rpl_flags()[..3], &[3, 7, 9]);
+
+        vm.execute_next().unwrap();
+        assert_eq!(vm.display.width(), 64);
+        assert!(vm.run());
+        vm.execute_next().unwrap();
+        assert!(!vm.run());
+    }
+
+    #[test]
+    fn test_flag_count() {
+        // LD R, VF; LD VF, R
+        let rom = [0xFF, 0x75, 0xFF, 0x85];
+        let mut vm = VM::try_from(&rom[..]).unwrap();
+        vm.regs = [1; 16];
+        match vm.execute_next() {
+            Err(VmError::FlagOutOfRange { pc: 0x200, x: 0xF, count: 8 }) => (),
+            res => panic!("Unexpected result {:?}", res)
+        }
+        assert_eq!(vm.rpl_flags(), &[0; 16]);
+
+        let mut vm = VmBuilder::new().quirks(Quirks::xochip()).build(&rom).unwrap();
+        vm.regs = [1; 16];
+        vm.execute_next().unwrap();
+        vm.regs = [0; 16];
+        vm.execute_next().unwrap();
+        assert_eq!(vm.regs, [1; 16]);
+    }
+
+    #[test]
+    fn test_xochip_draw_planes() {
+        let mut vm = vm_with_sprite(&[0x80, 0xC0]);
+        vm.select_planes(PLANE_1 | PLANE_2);
+        vm.draw(0, 0, 1).unwrap();
+        assert_eq!(vm.display.color(0, 0), 3);
+        assert_eq!(vm.display.color(1, 0), 2);
+
+        vm.select_planes(PLANE_2);
+        vm.clear();
+        assert_eq!(vm.display.color(0, 0), 1);
+        vm.draw(0, 0, 2).unwrap();
+        assert_eq!((vm.display.color(0, 0), vm.display.color(0, 1), vm.display.color(1, 1)), (3, 2, 2));
+    }
+
+    #[test]
+    fn test_xochip_instructions() {
+        // LD I, long 0x8000; SAVE V1, V3; LOAD V6, V4; SE V0, 0; LD I, long 0; AUDIO; PITCH V1
+        let rom = [
+            0xF0, 0x00, 0x80, 0x00, 0x51, 0x32, 0x56, 0x43, 0x30, 0x00, 0xF0, 0x00,
+            0x00, 0x00, 0xF0, 0x02, 0xF1, 0x3A
+        ];
+        let mut vm = VmBuilder::new().memory_size(XO_MEMORY_SIZE).build(&rom).unwrap();
+        vm.regs[1..4].copy_from_slice(&[1, 2, 3]);
+
+        vm.execute_next().unwrap();
+        assert_eq!((vm.pc, vm.i), (0x204, 0x8000));
+        vm.execute_next().unwrap();
+        assert_eq!(&vm.memory[0x8000..0x8003], &[1, 2, 3]);
+        vm.execute_next().unwrap();
+        assert_eq!(&vm.regs[4..7], &[3, 2, 1]);
+
+        vm.execute_next().unwrap();
+        assert_eq!(vm.pc, 0x20E);
+        vm.execute_next().unwrap();
+        assert_eq!(&vm.audio_pattern()[..], &vm.memory[0x8000..0x8010]);
+        vm.execute_next().unwrap();
+        assert_eq!(vm.pitch, 1);
+        assert!(vm.playback_rate() < 4000.0);
+    }
+}